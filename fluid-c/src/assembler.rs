@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use fluid_vm::*;
+
+/// names of typed literals indexed by their type tag, they are written like values of Immediate
+const LITERALS: &[&str] = &["U8", "U16", "U32", "U64", "I8", "I16", "I32", "I64", "F32", "F64", "BOOL"];
+
+/// type tag of immediate that refers to a constant
+const CONSTANT: u8 = 13;
+
+/// words between operands that are skipped, disassemble writes them to make operands readable
+const FILLERS: &[&str] = &["->", "as"];
+
+/// error of assembler at line and column of source, both start at 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// word of source with its position
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+/// label used by an operand, its offset is written once every label is known
+struct Fixup<'a> {
+    label: Token<'a>,
+    offset: usize,
+    tag: u8,
+}
+
+/// assembles source into container with lines of instructions in debug info,
+/// name is name of source file
+///
+/// every line holds labels followed by at most one instruction, `;` starts a comment,
+/// a label is a name followed by `:` and is replaced by its byte offset when it is used
+/// as value of an integer immediate, a number followed by `:` is an offset written by
+/// disassemble and is skipped
+///
+/// operands follow the mnemonic in the order of OPCODES, an immediate is a type and a
+/// literal (`push u16 255`), a typed literal (`push U16(255)`) or just `input_max` or
+/// `output_max`, a type is a type name (`cast f64`) and an address is an integer (`load 4 i64`)
+///
+/// `.const name value` adds a constant to the program where value is a literal like `u64 7`,
+/// a string in quotes or `bytes` followed by bytes, an immediate written as `#name` or `#3`
+/// refers to a constant by its name or its index, constants are numbered in the order of
+/// their definitions
+///
+/// output of disassemble is accepted too: `->` and `as` between operands are skipped,
+/// an immediate can be wrapped as `input[...]` or `output[...]` where a bare integer is a u8,
+/// and an address can start with `addr=` or `len=`, `.byte` emits its operands as bytes
+///
+/// integers can be written in decimal, hexadecimal (`0xff`), octal (`0o17`) or binary (`0b101`)
+/// with `_` between digits, floats accept `inf` and `nan`, bools are `true` or `false`
+pub fn assemble(source: &str, name: &str) -> Result<Container, AsmError> {
+    let assembly = assemble_lines(source)?;
+    Ok(Container {
+        code: assembly.bytecode,
+        constants: assembly.constants,
+        debug: Some(DebugInfo { source: name.to_string(), lines: assembly.lines }),
+    })
+}
+
+/// bytecode, constants and lines of instructions of source
+struct Assembly {
+    bytecode: Vec<u8>,
+    constants: Vec<Constant>,
+    lines: Vec<SourceLine>,
+}
+
+fn assemble_lines(source: &str) -> Result<Assembly, AsmError> {
+    let mut bytecode = Vec::new();
+    let mut constants = Vec::new();
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut names = HashMap::new();
+    let mut fixups = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let mut tokens = tokenize(line, index + 1)?.into_iter().peekable();
+
+        while let Some(token) = tokens.next_if(|token| token.text.ends_with(':')) {
+            let name = &token.text[..token.text.len() - 1];
+            if name.parse::<usize>().is_ok() {
+                continue;
+            }
+            if !is_label(name) {
+                return Err(error(token, format!("invalid label `{}`", name)));
+            }
+            if labels.insert(name, bytecode.len()).is_some() {
+                return Err(error(token, format!("label `{}` is defined more than once", name)));
+            }
+        }
+
+        let Some(mnemonic) = tokens.next() else {
+            continue;
+        };
+
+        if mnemonic.text == ".const" {
+            if constants.len() > u16::MAX as usize {
+                return Err(error(mnemonic, format!("more than {} constants", u16::MAX as usize + 1)));
+            }
+            let name = expect(tokens.next(), mnemonic, "name of constant")?;
+            if !is_label(name.text) {
+                return Err(error(name, format!("invalid name of constant `{}`", name.text)));
+            }
+            if names.insert(name.text, constants.len()).is_some() {
+                return Err(error(name, format!("constant `{}` is defined more than once", name.text)));
+            }
+
+            constants.push(constant(&mut tokens, mnemonic)?);
+            if let Some(token) = operand(&mut tokens) {
+                return Err(error(token, format!("unexpected operand `{}`", token.text)));
+            }
+            continue;
+        }
+
+        lines.push(SourceLine { offset: bytecode.len(), line: index + 1 });
+        if mnemonic.text == ".byte" {
+            while let Some(token) = operand(&mut tokens) {
+                bytecode.extend(encode(token, 0)?);
+            }
+            continue;
+        }
+
+        let opcode = opcode_by_name(mnemonic.text)
+            .ok_or_else(|| error(mnemonic, format!("unknown mnemonic `{}`", mnemonic.text)))?;
+        bytecode.push(opcode);
+
+        for kind in opcode_info(opcode).unwrap().operands {
+            match kind {
+                Operand::Immediate => {
+                    let (tag, literal) = immediate(&mut tokens, mnemonic)?;
+                    bytecode.push(tag);
+                    let Some(literal) = literal else {
+                        continue;
+                    };
+
+                    if tag == CONSTANT && !is_label(literal.text) {
+                        let index = literal.text.parse::<u16>()
+                            .map_err(|_| error(literal, format!("invalid constant `#{}`", literal.text)))?;
+                        bytecode.extend(index.to_be_bytes());
+                    } else if tag == CONSTANT || tag < 8 && is_label(literal.text) {
+                        fixups.push(Fixup { label: literal, offset: bytecode.len(), tag });
+                        bytecode.resize(bytecode.len() + immediate_size(tag).unwrap(), 0);
+                    } else {
+                        bytecode.extend(encode(literal, tag)?);
+                    }
+                }
+
+                Operand::Type => {
+                    let token = expect(operand(&mut tokens), mnemonic, "type")?;
+                    bytecode.push(type_tag(token)?);
+                }
+
+                Operand::Address => {
+                    let token = expect(operand(&mut tokens), mnemonic, "address")?;
+                    let token = strip(token, "addr=", "").or_else(|| strip(token, "len=", "")).unwrap_or(token);
+                    bytecode.extend(encode(token, 3)?);
+                }
+            }
+        }
+
+        if let Some(token) = operand(&mut tokens) {
+            return Err(error(token, format!("unexpected operand `{}`", token.text)));
+        }
+    }
+
+    for fixup in fixups {
+        let name = fixup.label.text;
+        let bytes = if fixup.tag == CONSTANT {
+            let index = *names.get(name).ok_or_else(|| error(fixup.label, format!("undefined constant `{}`", name)))?;
+            (index as u16).to_be_bytes().to_vec()
+        } else {
+            let offset = *labels.get(name).ok_or_else(|| error(fixup.label, format!("undefined label `{}`", name)))?;
+            let value = integer(offset as i128, fixup.tag).ok_or_else(|| {
+                error(fixup.label, format!("offset {} of label `{}` does not fit into {}", offset, name, TYPES[fixup.tag as usize]))
+            })?;
+            value.encode().unwrap()[1..].to_vec()
+        };
+        bytecode[fixup.offset..fixup.offset + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    Ok(Assembly { bytecode, constants, lines })
+}
+
+/// splits line into tokens, drops comment, a string in quotes is one token
+fn tokenize(line: &str, number: usize) -> Result<Vec<Token<'_>>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+
+        let mut end = line.len();
+        if c == '"' {
+            let mut escaped = false;
+            let mut closed = false;
+            for (index, c) in chars.by_ref() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => {
+                        end = index + 1;
+                        closed = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            if !closed {
+                let column = line[..start].chars().count() + 1;
+                return Err(AsmError { line: number, column, message: "string is not closed".to_string() });
+            }
+        } else {
+            while let Some(&(index, c)) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    end = index;
+                    break;
+                }
+                chars.next();
+            }
+        }
+
+        tokens.push(Token {
+            text: &line[start..end],
+            line: number,
+            column: line[..start].chars().count() + 1,
+        });
+    }
+
+    Ok(tokens)
+}
+
+fn error(token: Token, message: String) -> AsmError {
+    AsmError {
+        line: token.line,
+        column: token.column,
+        message,
+    }
+}
+
+/// returns token, fails at mnemonic if operand is missing
+fn expect<'a>(token: Option<Token<'a>>, mnemonic: Token, what: &str) -> Result<Token<'a>, AsmError> {
+    token.ok_or_else(|| error(mnemonic, format!("`{}` is missing {}", mnemonic.text, what)))
+}
+
+/// returns next operand, skips fillers
+fn operand<'a>(tokens: &mut impl Iterator<Item = Token<'a>>) -> Option<Token<'a>> {
+    tokens.find(|token| !FILLERS.contains(&token.text))
+}
+
+/// returns text of token between prefix and suffix
+fn strip<'a>(token: Token<'a>, prefix: &str, suffix: &str) -> Option<Token<'a>> {
+    let text = token.text.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    Some(Token {
+        text,
+        line: token.line,
+        column: token.column + prefix.chars().count(),
+    })
+}
+
+/// reads value of constant, a string, `bytes` followed by bytes or an immediate with a literal
+fn constant<'a>(tokens: &mut impl Iterator<Item = Token<'a>>, mnemonic: Token) -> Result<Constant, AsmError> {
+    let mut tokens = tokens.peekable();
+    let token = expect(tokens.peek().copied(), mnemonic, "value of constant")?;
+    if token.text.starts_with('"') {
+        tokens.next();
+        return Ok(Constant::Bytes(string(token)?));
+    }
+
+    if token.text == "bytes" {
+        tokens.next();
+        let mut bytes = Vec::new();
+        while let Some(token) = operand(&mut tokens) {
+            bytes.extend(encode(token, 0)?);
+        }
+        return Ok(Constant::Bytes(bytes));
+    }
+
+    match immediate(&mut tokens, mnemonic)? {
+        (tag, Some(literal)) if tag <= 10 && !(tag < 8 && is_label(literal.text)) => Ok(Constant::Value(value(literal, tag)?)),
+        _ => Err(error(token, "constant has to be a string, bytes or a literal".to_string())),
+    }
+}
+
+/// reads immediate operand, returns its type tag and literal, input_max and output_max have no literal
+fn immediate<'a>(tokens: &mut impl Iterator<Item = Token<'a>>, mnemonic: Token) -> Result<(u8, Option<Token<'a>>), AsmError> {
+    let token = expect(operand(tokens), mnemonic, "type of immediate")?;
+    let index = strip(token, "input[", "]").or_else(|| strip(token, "output[", "]"));
+    let token = index.unwrap_or(token);
+
+    match token.text {
+        "input_max" => return Ok((11, None)),
+        "output_max" => return Ok((12, None)),
+        _ => {}
+    }
+
+    if let Some(name) = strip(token, "#", "") {
+        return Ok((CONSTANT, Some(name)));
+    }
+
+    if let Some((name, _)) = token.text.split_once('(') {
+        if let Some(tag) = LITERALS.iter().position(|literal| *literal == name) {
+            let literal = strip(token, &format!("{}(", name), ")")
+                .ok_or_else(|| error(token, format!("invalid literal `{}`", token.text)))?;
+            return Ok((tag as u8, Some(literal)));
+        }
+    }
+
+    if index.is_some() && parse_integer(token.text).is_some() {
+        return Ok((0, Some(token)));
+    }
+
+    let tag = type_tag(token)?;
+    let literal = expect(operand(tokens), mnemonic, "value of immediate")?;
+    Ok((tag, Some(literal)))
+}
+
+/// returns true if text is a name of label
+fn is_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// returns tag of type name
+fn type_tag(token: Token) -> Result<u8, AsmError> {
+    TYPES.iter().position(|name| *name == token.text)
+        .map(|tag| tag as u8)
+        .ok_or_else(|| error(token, format!("unknown type `{}`", token.text)))
+}
+
+/// returns big endian bytes of literal as value of type tag
+fn encode(literal: Token, tag: u8) -> Result<Vec<u8>, AsmError> {
+    Ok(value(literal, tag)?.encode().unwrap()[1..].to_vec())
+}
+
+/// returns literal as value of type tag
+fn value(literal: Token, tag: u8) -> Result<Immediate, AsmError> {
+    let text = literal.text.replace('_', "");
+    let invalid = |what: &str| error(literal, format!("invalid {} `{}`", what, literal.text));
+
+    let value = match tag {
+        0..=7 => {
+            let value = parse_integer(&text).ok_or_else(|| invalid("integer"))?;
+            integer(value, tag).ok_or_else(|| {
+                error(literal, format!("{} does not fit into {}", literal.text, TYPES[tag as usize]))
+            })?
+        }
+        8 => Immediate::F32(text.parse().map_err(|_| invalid("float"))?),
+        9 => Immediate::F64(text.parse().map_err(|_| invalid("float"))?),
+        10 => match literal.text {
+            "true" => Immediate::BOOL(true),
+            "false" => Immediate::BOOL(false),
+            _ => return Err(invalid("bool")),
+        },
+        _ => unreachable!("types without value are encoded by their tag"),
+    };
+
+    Ok(value)
+}
+
+/// returns integer as value of type tag, None if it does not fit
+fn integer(value: i128, tag: u8) -> Option<Immediate> {
+    let value = match tag {
+        0 => Immediate::U8(value.try_into().ok()?), // u8
+        1 => Immediate::U16(value.try_into().ok()?), // u16
+        2 => Immediate::U32(value.try_into().ok()?), // u32
+        3 => Immediate::U64(value.try_into().ok()?), // u64
+        4 => Immediate::I8(value.try_into().ok()?), // i8
+        5 => Immediate::I16(value.try_into().ok()?), // i16
+        6 => Immediate::I32(value.try_into().ok()?), // i32
+        7 => Immediate::I64(value.try_into().ok()?), // i64
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// returns bytes of string literal in quotes, accepts escapes \\, \", \n, \r, \t, \0 and \xff
+fn string(literal: Token) -> Result<Vec<u8>, AsmError> {
+    let invalid = || error(literal, format!("invalid string {}", literal.text));
+    let text = literal.text.strip_prefix('"').and_then(|text| text.strip_suffix('"')).ok_or_else(invalid)?;
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+
+        let byte = match chars.next().ok_or_else(invalid)? {
+            '\\' => b'\\',
+            '"' => b'"',
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            '0' => 0,
+            'x' => {
+                let digits: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&digits, 16).map_err(|_| invalid())?
+            }
+            _ => return Err(invalid()),
+        };
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+/// parses decimal, hexadecimal, octal or binary integer with optional sign
+fn parse_integer(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") => (16, &digits[2..]),
+        Some("0o") => (8, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits),
+    };
+
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return None;
+    }
+
+    let value = i128::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
@@ -1,9 +1,190 @@
+mod assembler;
+#[cfg(test)]
+mod tests;
+
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::process;
+
 use fluid_vm::*;
+use assembler::*;
+
+const USAGE: &str = "Welcome to Fluid, the flowing VM!
+
+usage:
+    fluid-c run <file>                  executes .fluidc container or assembles and executes .fasm program
+    fluid-c run --heap <file>           executes program and prints objects left on heap
+    fluid-c run --leaks <file>          executes program and prints objects it never freed
+    fluid-c asm <file.fasm> <output>    assembles program and writes .fluidc container to output
+    fluid-c disasm <file.fluidc>        prints instructions of container";
 
 fn main() {
-    println!("Welcome to Fluid, the flowing VM!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["run", path] => run(path, false, false),
+        ["run", "--heap", path] => run(path, true, false),
+        ["run", "--leaks", path] => run(path, false, true),
+        ["asm", path, output] => asm(path, output),
+        ["disasm", path] => disasm(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    match result {
+        Ok(code) => process::exit(code),
+        Err(error) => {
+            eprintln!("Fluid, error: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// executes container or program, returns exit code, summary of heap and leaks are printed
+/// to stderr after execution even if it failed
+fn run(path: &str, heap: bool, leaks: bool) -> Result<i32, String> {
+    let mut vm = if path.ends_with(".fluidc") {
+        VM::from_file(path).map_err(|error| format!("{}: {}", path, error))?
+    } else {
+        VM::from_container(assemble_file(path)?)
+    };
+    if leaks {
+        vm.set_leak_report(|objects| eprint!("{}", leak_report(objects)));
+    }
+    let result = vm.execute();
+    if heap {
+        eprint!("{}", heap_summary(&vm));
+    }
+    let state = result.map_err(|error| error.to_string())?;
+    exit_code(&state)
+}
+
+/// returns statistics of heap and a line for every live object
+fn heap_summary(vm: &VM) -> String {
+    let stats = vm.heap_stats();
+    let mut summary = format!(
+        "heap: {} objects, {} bytes (peak {} bytes), {} allocated, {} freed\n",
+        stats.objects, stats.bytes, stats.peak_bytes, stats.allocated, stats.freed,
+    );
+
+    for object in vm.heap_objects() {
+        let origin = match object.ip {
+            Some(ip) => format!("allocated at ip {}", ip),
+            None => "constant".to_string(),
+        };
+        summary += &format!("  {:<8} {:<12} {:>8} bytes  {}\n", object.address.to_string(), object_type(&object), object.size, origin);
+    }
+    summary
+}
+
+/// returns a line for every object that was never freed
+fn leak_report(objects: &[HeapObjectInfo]) -> String {
+    let mut report = format!("leaked {} objects\n", objects.len());
+    for object in objects {
+        report += &format!("  {:<8} {:<12} allocated at ip {}\n", object.address.to_string(), object_type(object), object.ip.unwrap_or(0));
+    }
+    report
+}
+
+/// returns type of object as it is written in .fasm, arrays have their length in brackets
+fn object_type(object: &HeapObjectInfo) -> String {
+    let name = TYPES.get(object.tag as usize).copied().unwrap_or("?");
+    match object.length {
+        Some(length) => format!("{}[{}]", name, length),
+        None => name.to_string(),
+    }
+}
+
+/// assembles program and writes container to output
+fn asm(path: &str, output: &str) -> Result<i32, String> {
+    let container = assemble_file(path)?;
+    container.write_file(output).map_err(|error| format!("{}: {}", output, error))?;
+    Ok(0)
+}
+
+/// prints instructions of container with lines of source they were assembled from
+fn disasm(path: &str) -> Result<i32, String> {
+    let container = Container::read_file(path).map_err(|error| format!("{}: {}", path, error))?;
+    for (index, constant) in container.constants.iter().enumerate() {
+        println!(".const c{} {}", index, constant_text(constant));
+    }
+
+    for instruction in disassemble(&container.code) {
+        match container.debug.as_ref().and_then(|debug| Some((debug, debug.line_of(instruction.offset)?))) {
+            Some((debug, line)) if instruction.problem.is_none() => println!("{:<40}  ; {}:{}", instruction.to_string(), debug.source, line),
+            _ => println!("{}", instruction),
+        }
+    }
+    Ok(0)
+}
+
+/// returns value of constant as it is written in .fasm
+fn constant_text(constant: &Constant) -> String {
+    match constant {
+        Constant::Value(value) => format!("{:?}", value),
+        Constant::Bytes(bytes) => match std::str::from_utf8(bytes) {
+            Ok(text) if !text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\0')) => {
+                let mut quoted = String::from("\"");
+                for c in text.chars() {
+                    match c {
+                        '\\' => quoted.push_str("\\\\"),
+                        '"' => quoted.push_str("\\\""),
+                        '\n' => quoted.push_str("\\n"),
+                        '\r' => quoted.push_str("\\r"),
+                        '\t' => quoted.push_str("\\t"),
+                        '\0' => quoted.push_str("\\0"),
+                        c => quoted.push(c),
+                    }
+                }
+                quoted + "\""
+            }
+            _ => {
+                let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+                format!("bytes {}", bytes.join(" "))
+            }
+        },
+    }
+}
+
+fn assemble_file(path: &str) -> Result<Container, String> {
+    let source = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+    assemble(&source, path).map_err(|error| format!("{}:{}", path, error))
+}
+
+/// exit codes the process keeps, other codes are truncated by the platform
+const EXIT_CODES: RangeInclusive<i32> = if cfg!(unix) { 0..=255 } else { i32::MIN..=i32::MAX };
+
+/// converts exit value of halt to process exit code, 0 if there is none, fails if value
+/// is not an integer or does not fit into EXIT_CODES
+fn exit_code(state: &ExitState) -> Result<i32, String> {
+    let value = match state.status {
+        ExitStatus::Halted(Some(value)) => value,
+        _ => return Ok(0),
+    };
+
+    let code = match value {
+        Immediate::U8(v) => v as i128,
+        Immediate::U16(v) => v as i128,
+        Immediate::U32(v) => v as i128,
+        Immediate::U64(v) => v as i128,
+        Immediate::I8(v) => v as i128,
+        Immediate::I16(v) => v as i128,
+        Immediate::I32(v) => v as i128,
+        Immediate::I64(v) => v as i128,
+        Immediate::F32(v) if v.fract() == 0.0 => v as i128,
+        Immediate::F64(v) if v.fract() == 0.0 => v as i128,
+        Immediate::BOOL(v) => v as i128,
+        Immediate::ADDRESS(v) => v.to_u64() as i128,
+        Immediate::NONE() => 0,
+        _ => i128::MAX,
+    };
 
-    let bytecode: Vec<u8> = vec![1, 1, 0, 255, 1, 0, 0, 17];
-    let mut vm = VM::new(bytecode);
-    vm.execute();
+    match i32::try_from(code) {
+        Ok(code) if EXIT_CODES.contains(&code) => Ok(code),
+        _ => Err(format!("exit value {:?} does not fit into an exit code", value)),
+    }
 }
@@ -0,0 +1,264 @@
+use fluid_vm::*;
+
+use crate::assembler::*;
+
+fn assemble_code(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble(source, "test.fasm").map(|container| container.code)
+}
+
+/// returns line, column and message of error
+fn fail(source: &str) -> (usize, usize, String) {
+    let error = assemble_code(source).unwrap_err();
+    (error.line, error.column, error.message)
+}
+
+#[test]
+fn examples_verify() {
+    let container = assemble(include_str!("../examples/count.fasm"), "count.fasm").unwrap();
+    assert!(verify_container(&container).is_ok());
+}
+
+#[test]
+fn immediates_are_tagged_big_endian() {
+    assert_eq!(assemble_code("push u16 255").unwrap(), vec![1, 1, 0, 255]);
+    assert_eq!(assemble_code("push i32 -2").unwrap(), vec![1, 6, 255, 255, 255, 254]);
+    assert_eq!(assemble_code("push f32 1.5").unwrap(), [vec![1, 8], 1.5f32.to_be_bytes().to_vec()].concat());
+    assert_eq!(assemble_code("push bool true").unwrap(), vec![1, 10, 1]);
+    assert_eq!(assemble_code("push u8 0xf_f").unwrap(), vec![1, 0, 255]);
+    assert_eq!(assemble_code("push u8 0b101").unwrap(), vec![1, 0, 5]);
+    assert_eq!(assemble_code("set u8 3 u8 2").unwrap(), vec![4, 0, 3, 0, 2]);
+    assert_eq!(assemble_code("popi output_max").unwrap(), vec![3, 12]);
+}
+
+#[test]
+fn types_and_addresses_are_encoded() {
+    assert_eq!(assemble_code("gen f32 16").unwrap(), vec![9, 8, 0, 0, 0, 0, 0, 0, 0, 16]);
+    assert_eq!(assemble_code("load 4 i64").unwrap(), vec![12, 0, 0, 0, 0, 0, 0, 0, 4, 7]);
+    assert_eq!(assemble_code("loadi 4 input_max bool").unwrap(), vec![13, 0, 0, 0, 0, 0, 0, 0, 4, 11, 10]);
+    assert_eq!(assemble_code("castc u64").unwrap(), vec![32, 3]);
+}
+
+#[test]
+fn comments_and_empty_lines_are_skipped() {
+    let source = "; program\n\n   nop   ; does nothing\n\tjmp\n";
+    assert_eq!(assemble_code(source).unwrap(), vec![0, 17]);
+}
+
+#[test]
+fn labels_resolve_to_offsets() {
+    let source = "
+        push u8 end     ; 0
+        jmp             ; 3
+start:  nop             ; 4
+end:    push u16 start  ; 5
+    ";
+    assert_eq!(assemble_code(source).unwrap(), vec![1, 0, 5, 17, 0, 1, 1, 0, 4]);
+}
+
+#[test]
+fn errors_have_line_and_column() {
+    assert_eq!(fail("nop\n  pus u8 1"), (2, 3, "unknown mnemonic `pus`".to_string()));
+    assert_eq!(fail("push u8 256"), (1, 9, "256 does not fit into u8".to_string()));
+    assert_eq!(fail("push u9 1"), (1, 6, "unknown type `u9`".to_string()));
+    assert_eq!(fail("gen input_max 1"), (1, 5, "unknown type `input_max`".to_string()));
+    assert_eq!(fail("push bool yes"), (1, 11, "invalid bool `yes`".to_string()));
+    assert_eq!(fail("push u8"), (1, 1, "`push` is missing value of immediate".to_string()));
+    assert_eq!(fail("add u8 1"), (1, 5, "unexpected operand `u8`".to_string()));
+    assert_eq!(fail("push u8 nowhere"), (1, 9, "undefined label `nowhere`".to_string()));
+    assert_eq!(fail("a: nop\na: nop"), (2, 1, "label `a` is defined more than once".to_string()));
+}
+
+#[test]
+fn label_has_to_fit_into_type() {
+    let source = format!("{}far: push u8 far", "nop\n".repeat(256));
+    assert_eq!(fail(&source), (257, 14, "offset 256 of label `far` does not fit into u8".to_string()));
+}
+
+#[test]
+fn example_counts_to_ten() {
+    let bytecode = assemble_code(include_str!("../examples/count.fasm")).unwrap();
+    let mut vm = VM::new(bytecode);
+    let state = vm.execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(Some(Immediate::U32(10))));
+}
+
+#[test]
+fn disassembly_assembles_into_same_bytes() {
+    let source = "
+start:  nop
+        push u16 255
+        pop
+        popi u8 1
+        set u8 3 u8 2
+        get input_max
+        geti u32 1 output_max
+        clear_i
+        clear_o
+        gen f32 16
+        save f64 -1.25
+        savei bool true u8 0
+        load 4 i64
+        loadi 4 u16 300 u8
+        less
+        great
+        eq
+        push i8 start
+        jmp
+        add
+        sub
+        mul
+        div
+        rem
+        neg
+        abs
+        jmp_if
+        jmp_unless
+        call u8 2
+        ret u8 1
+        halt
+        ovf u8 1
+        cast f32
+        castc i16
+        push f32 nan
+        push f64 -inf
+        push f32 0.1
+    ";
+    let bytecode = assemble_code(source).unwrap();
+    let listing: Vec<String> = disassemble(&bytecode).iter().map(|instruction| instruction.to_string()).collect();
+
+    assert!(disassemble(&bytecode).iter().all(|instruction| instruction.problem.is_none()));
+    assert_eq!(assemble_code(&listing.join("\n")).unwrap(), bytecode);
+}
+
+#[test]
+fn undecodable_bytes_assemble_into_same_bytes() {
+    let bytecode = vec![255, 1, 0, 7, 9, 11, 1, 3];
+    let listing: Vec<String> = disassemble(&bytecode).iter().map(|instruction| instruction.to_string()).collect();
+    assert_eq!(assemble_code(&listing.join("\n")).unwrap(), bytecode);
+}
+
+#[test]
+fn container_has_lines_of_instructions() {
+    let container = assemble("; comment\nnop\n\nloop: push u8 loop\n", "loop.fasm").unwrap();
+    let debug = container.debug.unwrap();
+    assert_eq!(debug.source, "loop.fasm");
+    assert_eq!(debug.line_of(0), Some(2));
+    assert_eq!(debug.line_of(1), Some(4));
+}
+
+#[test]
+fn constants_are_stored_once() {
+    let source = "
+        .const big u64 0x1234_5678_9abc
+        push #big
+        push #big
+        push #0
+    ";
+    let container = assemble(source, "test.fasm").unwrap();
+    assert_eq!(container.constants, vec![Constant::Value(Immediate::U64(0x1234_5678_9abc))]);
+    assert_eq!(container.code, vec![1, 13, 0, 0, 1, 13, 0, 0, 1, 13, 0, 0]);
+}
+
+#[test]
+fn strings_and_bytes_are_constants() {
+    let source = r#"
+        .const text "a; \"b\"\n\x00"   ; comment
+        .const blob bytes 1 0xff
+        .const half F32(0.5)
+        push #blob
+    "#;
+    let container = assemble(source, "test.fasm").unwrap();
+    assert_eq!(container.constants, vec![
+        Constant::Bytes(b"a; \"b\"\n\0".to_vec()),
+        Constant::Bytes(vec![1, 255]),
+        Constant::Value(Immediate::F32(0.5)),
+    ]);
+    assert_eq!(container.code, vec![1, 13, 0, 1]);
+}
+
+#[test]
+fn constant_errors_have_line_and_column() {
+    assert_eq!(fail("push #nowhere"), (1, 7, "undefined constant `nowhere`".to_string()));
+    assert_eq!(fail(".const a u8 1\n.const a u8 2"), (2, 8, "constant `a` is defined more than once".to_string()));
+    assert_eq!(fail(".const a \"open"), (1, 10, "string is not closed".to_string()));
+    assert_eq!(fail(".const a input_max"), (1, 10, "constant has to be a string, bytes or a literal".to_string()));
+    assert_eq!(fail("push #70000"), (1, 7, "invalid constant `#70000`".to_string()));
+}
+
+#[test]
+fn constants_have_to_fit_into_u16_operands() {
+    let mut source: String = (0..=u16::MAX as usize).map(|index| format!(".const c{} u8 0\n", index)).collect();
+    source.push_str("push #c65535");
+    let container = assemble(&source, "test.fasm").unwrap();
+    assert_eq!(container.code, vec![1, 13, 255, 255]);
+
+    source.push_str("\n.const c65536 u8 0\npush #c65536");
+    assert_eq!(fail(&source), (65538, 1, "more than 65536 constants".to_string()));
+}
+
+#[test]
+fn constants_are_executed_and_listed() {
+    let source = "
+        .const code i64 -7
+        .const text \"tab\\there\"
+        .const blob bytes 0 1
+        push #text
+        push #code
+        halt
+    ";
+    let container = assemble(source, "test.fasm").unwrap();
+    let mut listing: Vec<String> = container.constants.iter().enumerate()
+        .map(|(index, constant)| format!(".const c{} {}", index, crate::constant_text(constant)))
+        .collect();
+    listing.extend(disassemble(&container.code).iter().map(|instruction| instruction.to_string()));
+
+    let assembled = assemble(&listing.join("\n"), "listing.fasm").unwrap();
+    assert_eq!((&assembled.code, &assembled.constants), (&container.code, &container.constants));
+
+    let state = VM::from_container(container).execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(Some(Immediate::I64(-7))));
+}
+
+#[test]
+fn heap_summary_lists_live_objects() {
+    let source = "
+        .const blob bytes 0 1 2
+        gen u16 3
+        save i32 -1
+        save bool true
+        get u8 2
+        free
+    ";
+    let mut vm = VM::from_container(assemble(source, "test.fasm").unwrap());
+    vm.execute().unwrap();
+    assert_eq!(crate::heap_summary(&vm), "\
+heap: 3 objects, 13 bytes (peak 14 bytes), 4 allocated, 1 freed
+  0        u8[3]               3 bytes  constant
+  1        u16[3]              6 bytes  allocated at ip 0
+  2        i32                 4 bytes  allocated at ip 10
+");
+}
+
+#[test]
+fn leak_report_lists_objects_with_ip() {
+    let objects = [HeapObjectInfo { address: Address::new(3, 1), tag: 9, length: Some(2), size: 16, ip: Some(12) }];
+    assert_eq!(crate::leak_report(&objects), "leaked 1 objects\n  3 (generation 1) f64[2]       allocated at ip 12\n");
+}
+
+#[test]
+fn exit_values_that_do_not_fit_are_errors() {
+    let exit_code = |value| crate::exit_code(&ExitState { status: ExitStatus::Halted(Some(value)), ip: 0, instructions: 1 });
+    assert_eq!(exit_code(Immediate::U8(7)), Ok(7));
+    assert_eq!(exit_code(Immediate::F64(3.0)), Ok(3));
+    assert_eq!(exit_code(Immediate::BOOL(true)), Ok(1));
+    assert_eq!(crate::exit_code(&ExitState { status: ExitStatus::Finished, ip: 0, instructions: 0 }), Ok(0));
+
+    assert_eq!(exit_code(Immediate::U64(1 << 32)), Err("exit value U64(4294967296) does not fit into an exit code".to_string()));
+    assert!(exit_code(Immediate::F32(2.5)).is_err());
+    assert!(exit_code(Immediate::F64(f64::NAN)).is_err());
+    assert!(exit_code(Immediate::I64(i64::MIN)).is_err());
+    if cfg!(unix) {
+        assert!(exit_code(Immediate::U16(256)).is_err());
+        assert!(exit_code(Immediate::I8(-1)).is_err());
+    }
+}
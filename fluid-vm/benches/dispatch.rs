@@ -0,0 +1,74 @@
+use std::time::Instant;
+
+use fluid_vm::*;
+
+/// counts from 0 to n, every iteration executes 10 instructions
+fn counting_loop(n: u32) -> Vec<u8> {
+    let mut bytecode = vec![1, 2, 0, 0, 0, 0]; // 0: push u32 0
+    bytecode.extend([1, 2, 0, 0, 0, 1]); // 6: push u32 1
+    bytecode.push(18); // 12: add
+    bytecode.extend([5, 0, 0]); // 13: get u8 0
+    bytecode.extend([5, 0, 0]); // 16: get u8 0
+    bytecode.extend([1, 2]); // 19: push u32 n
+    bytecode.extend(n.to_be_bytes());
+    bytecode.push(15); // 25: great
+    bytecode.push(2); // 26: pop
+    bytecode.extend([1, 0, 6]); // 27: push u8 6
+    bytecode.push(8); // 30: clear_o
+    bytecode.push(25); // 31: jmp_if
+    bytecode
+}
+
+/// calls a function that adds its two arguments n times
+fn calling_loop(n: u32) -> Vec<u8> {
+    let mut bytecode = vec![1, 2, 0, 0, 0, 0]; // 0: push u32 0
+    bytecode.extend([1, 2, 0, 0, 0, 1]); // 6: push u32 1
+    bytecode.extend([1, 0, 38]); // 12: push u8 38
+    bytecode.extend([27, 0, 2]); // 15: call u8 2
+    bytecode.extend([5, 0, 0]); // 18: get u8 0
+    bytecode.extend([5, 0, 0]); // 21: get u8 0
+    bytecode.extend([1, 2]); // 24: push u32 n
+    bytecode.extend(n.to_be_bytes());
+    bytecode.push(15); // 30: great
+    bytecode.push(2); // 31: pop
+    bytecode.extend([1, 0, 6]); // 32: push u8 6
+    bytecode.push(8); // 35: clear_o
+    bytecode.push(25); // 36: jmp_if
+    bytecode.push(29); // 37: halt
+    bytecode.push(18); // 38: add
+    bytecode.extend([28, 0, 1]); // 39: ret u8 1
+    bytecode
+}
+
+/// returns best time of runs in seconds and number of executed instructions
+fn measure(bytecode: &[u8], execute: fn(&mut VM) -> Result<ExitState, VmError>) -> (f64, u64) {
+    let runs = 15;
+    let mut best = f64::MAX;
+    let mut instructions = 0;
+    for _ in 0..runs {
+        let mut vm = VM::new(bytecode.to_vec());
+        let start = Instant::now();
+        let state = execute(&mut vm).unwrap();
+        best = best.min(start.elapsed().as_secs_f64());
+        instructions = state.instructions;
+    }
+
+    (best, instructions)
+}
+
+/// compares pre-decoded dispatch with decoding every instruction again before it runs
+fn bench(name: &str, bytecode: Vec<u8>) {
+    let (decoding, _) = measure(&bytecode, VM::execute_decoding);
+    let (best, instructions) = measure(&bytecode, VM::execute);
+    let per_instruction = |seconds: f64| seconds * 1e9 / instructions as f64;
+
+    println!(
+        "{:<14} {:>10} instructions  {:>8.2} ms  {:>7.2} ns/instruction  decoding every step {:>7.2} ns/instruction  {:.2}x",
+        name, instructions, best * 1e3, per_instruction(best), per_instruction(decoding), decoding / best,
+    );
+}
+
+fn main() {
+    bench("counting_loop", counting_loop(1_000_000));
+    bench("calling_loop", calling_loop(300_000));
+}
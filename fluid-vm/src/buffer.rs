@@ -1,5 +1,3 @@
-use std::fmt::Debug;
-
 use crate::tools::*;
 
 pub struct Buffer
@@ -14,12 +12,20 @@ impl Buffer {
         }
     }
 
+    /// returns buffer that holds values, the last one is on top
+    pub fn from_values(data: Vec<Immediate>) -> Self {
+        Self {
+            data,
+        }
+    }
+
     pub fn push(&mut self, value: Immediate) {
         self.data.push(value);
     }
 
-    pub fn pop(&mut self) -> Immediate {
-        self.data.pop().unwrap()
+    /// returns None if buffer is empty
+    pub fn pop(&mut self) -> Option<Immediate> {
+        self.data.pop()
     }
 
     pub fn get(&self, index: usize) -> Immediate {
@@ -29,17 +35,36 @@ impl Buffer {
         Immediate::NONE()
     }
 
-    pub fn set(&mut self, index: usize, value: Immediate) {
-        if self.data.len() > index {
-            self.data[index] = value;
-        } else {
-            self.data.resize(index + 1, Immediate::NONE());
-            self.data[index] = value;
+    /// sets value at index, returns value that was replaced, NONE if buffer was shorter,
+    /// None if buffer would grow beyond max_len values
+    pub fn set(&mut self, index: usize, value: Immediate, max_len: usize) -> Option<Immediate> {
+        if self.data.len() <= index {
+            let len = index.checked_add(1).filter(|&len| len <= max_len)?;
+            self.data.resize(len, Immediate::NONE());
         }
+        Some(std::mem::replace(&mut self.data[index], value))
     }
 
+    /// takes count values from the top, keeps their order, returns None if there are less values
+    pub fn split_off(&mut self, count: usize) -> Option<Buffer> {
+        let at = self.data.len().checked_sub(count)?;
+        Some(Self {
+            data: self.data.split_off(at),
+        })
+    }
+
+    /// pushes all values of other buffer, keeps their order
+    pub fn append(&mut self, mut other: Buffer) {
+        self.data.append(&mut other.data);
+    }
+
+    /// removes all values, keeps allocated capacity for values pushed later
     pub fn clear(&mut self) {
-        self.data = Vec::new();
+        self.data.clear();
+    }
+
+    pub fn as_slice(&self) -> &[Immediate] {
+        &self.data
     }
 
     pub fn len(&self) -> u64 {
@@ -0,0 +1,303 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::decoder::*;
+use crate::tools::*;
+
+/// first bytes of every container
+pub const MAGIC: [u8; 4] = *b"FLDC";
+
+/// version of the layout of a container
+pub const FORMAT_VERSION: u16 = 1;
+
+/// version of opcodes and operand encoding, changes whenever opcodes are added or old bytecode could run differently,
+/// changes of every version are listed in CHANGELOG.md
+pub const ISA_VERSION: u16 = 5;
+
+/// flag that is set when the container has a debug section
+pub const FLAG_DEBUG: u32 = 1;
+
+/// value stored in the constant section
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    /// value of type 0..=10, NONE and ADDRESS can not be stored
+    Value(Immediate),
+    /// bytes of a blob or an UTF-8 string
+    Bytes(Vec<u8>),
+}
+
+/// byte offset of an instruction with line of source it was assembled from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    pub offset: usize,
+    pub line: usize,
+}
+
+/// contents of the debug section
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DebugInfo {
+    /// name of source file
+    pub source: String,
+    /// lines of instructions ordered by offset
+    pub lines: Vec<SourceLine>,
+}
+
+impl DebugInfo {
+    /// returns line of source of instruction at offset
+    pub fn line_of(&self, offset: usize) -> Option<usize> {
+        let index = self.lines.binary_search_by_key(&offset, |line| line.offset).ok()?;
+        Some(self.lines[index].line)
+    }
+}
+
+/// program as it is stored in a .fluidc file
+///
+/// layout, every number is big endian:
+///
+/// ```text
+/// magic           4 bytes  "FLDC"
+/// format version  u16
+/// ISA version     u16
+/// flags           u32      FLAG_DEBUG
+/// code            u32 length, bytecode
+/// constants       u32 length, u32 count, constants
+/// debug           u32 length, debug info, only if FLAG_DEBUG is set
+/// ```
+///
+/// a constant is kind 0 followed by a type tag 0..=10 and a value encoded like an operand of push,
+/// or kind 1 followed by u32 length and bytes, debug info is u32 length and UTF-8 name of
+/// source, u32 count and pairs of u32 offset and u32 line
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Container {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    pub debug: Option<DebugInfo>,
+}
+
+/// error of reading or writing a container
+#[derive(Debug)]
+pub enum ContainerError {
+    /// file could not be read or written
+    Io(io::Error),
+    /// bytes do not start with MAGIC
+    BadMagic,
+    /// container was written in a format this VM can not read
+    UnsupportedFormat(u16),
+    /// bytecode was written for another version of opcodes
+    IncompatibleIsa(u16),
+    /// flags that are not known
+    UnknownFlags(u32),
+    /// section runs past the end of bytes or ends before its contents
+    Truncated,
+    /// bytes follow the last section or the contents of a section
+    TrailingBytes,
+    /// constant at index has an unknown kind or a value that can not be stored
+    InvalidConstant(usize),
+    /// name of source is not UTF-8
+    InvalidDebugInfo,
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::Io(error) => write!(f, "{}", error),
+            ContainerError::BadMagic => write!(f, "not a fluidc container"),
+            ContainerError::UnsupportedFormat(version) => {
+                write!(f, "unsupported container format {}, this VM reads format {}", version, FORMAT_VERSION)
+            }
+            ContainerError::IncompatibleIsa(version) => {
+                write!(f, "bytecode was built for ISA version {}, this VM runs version {}", version, ISA_VERSION)
+            }
+            ContainerError::UnknownFlags(flags) => write!(f, "unknown flags {:#x}", flags),
+            ContainerError::Truncated => write!(f, "container is truncated"),
+            ContainerError::TrailingBytes => write!(f, "unexpected bytes after the end of a section"),
+            ContainerError::InvalidConstant(index) => write!(f, "invalid constant #{}", index),
+            ContainerError::InvalidDebugInfo => write!(f, "invalid debug section"),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ContainerError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ContainerError {
+    fn from(error: io::Error) -> Self {
+        ContainerError::Io(error)
+    }
+}
+
+impl From<Fault> for ContainerError {
+    fn from(_: Fault) -> Self {
+        ContainerError::Truncated
+    }
+}
+
+impl Container {
+    /// returns container of bytecode without constants and debug info
+    pub fn new(code: Vec<u8>) -> Self {
+        Container {
+            code,
+            ..Default::default()
+        }
+    }
+
+    /// reads container, fails if it was written in another format or for another ISA version
+    pub fn read(bytes: &[u8]) -> Result<Self, ContainerError> {
+        let mut reader = Reader { bytecode: bytes, offset: 0 };
+        if reader.bytes::<4>().map_err(|_| ContainerError::BadMagic)? != MAGIC {
+            return Err(ContainerError::BadMagic);
+        }
+
+        let format = u16::from_be_bytes(reader.bytes()?);
+        if format != FORMAT_VERSION {
+            return Err(ContainerError::UnsupportedFormat(format));
+        }
+
+        let isa = u16::from_be_bytes(reader.bytes()?);
+        if isa != ISA_VERSION {
+            return Err(ContainerError::IncompatibleIsa(isa));
+        }
+
+        let flags = u32::from_be_bytes(reader.bytes()?);
+        if flags & !FLAG_DEBUG != 0 {
+            return Err(ContainerError::UnknownFlags(flags & !FLAG_DEBUG));
+        }
+
+        let code = section(&mut reader)?.to_vec();
+        let constants = read_constants(section(&mut reader)?)?;
+        let debug = match flags & FLAG_DEBUG {
+            0 => None,
+            _ => Some(read_debug(section(&mut reader)?)?),
+        };
+
+        if reader.offset != bytes.len() {
+            return Err(ContainerError::TrailingBytes);
+        }
+
+        Ok(Container { code, constants, debug })
+    }
+
+    /// returns bytes of container, fails if a constant can not be stored
+    pub fn write(&self) -> Result<Vec<u8>, ContainerError> {
+        let flags = if self.debug.is_some() { FLAG_DEBUG } else { 0 };
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(FORMAT_VERSION.to_be_bytes());
+        bytes.extend(ISA_VERSION.to_be_bytes());
+        bytes.extend(flags.to_be_bytes());
+        write_section(&mut bytes, &self.code);
+        write_section(&mut bytes, &write_constants(&self.constants)?);
+        if let Some(debug) = &self.debug {
+            write_section(&mut bytes, &write_debug(debug));
+        }
+
+        Ok(bytes)
+    }
+
+    /// reads container from file
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, ContainerError> {
+        Container::read(&fs::read(path)?)
+    }
+
+    /// writes container to file
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<(), ContainerError> {
+        fs::write(path, self.write()?)?;
+        Ok(())
+    }
+}
+
+/// returns contents of section that starts with u32 length, fails if it runs past the end of bytes
+pub(crate) fn section<'a>(reader: &mut Reader<'a>) -> Result<&'a [u8], Fault> {
+    let length = u32::from_be_bytes(reader.bytes()?) as usize;
+    let start = reader.offset;
+    let contents = reader.bytecode.get(start..start + length).ok_or(Fault::TruncatedOperand)?;
+    reader.offset += length;
+    Ok(contents)
+}
+
+pub(crate) fn write_section(bytes: &mut Vec<u8>, contents: &[u8]) {
+    bytes.extend((contents.len() as u32).to_be_bytes());
+    bytes.extend(contents);
+}
+
+fn read_constants(bytes: &[u8]) -> Result<Vec<Constant>, ContainerError> {
+    let mut reader = Reader { bytecode: bytes, offset: 0 };
+    let count = u32::from_be_bytes(reader.bytes()?) as usize;
+    let mut constants = Vec::new();
+
+    for index in 0..count {
+        let constant = match reader.byte()? {
+            0 => match reader.value() {
+                Ok(Value::Immediate(value)) => Constant::Value(value),
+                Err(Fault::TruncatedOperand) => return Err(ContainerError::Truncated),
+                _ => return Err(ContainerError::InvalidConstant(index)),
+            },
+            1 => Constant::Bytes(section(&mut reader)?.to_vec()),
+            _ => return Err(ContainerError::InvalidConstant(index)),
+        };
+        constants.push(constant);
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(ContainerError::TrailingBytes);
+    }
+
+    Ok(constants)
+}
+
+fn write_constants(constants: &[Constant]) -> Result<Vec<u8>, ContainerError> {
+    let mut bytes = (constants.len() as u32).to_be_bytes().to_vec();
+    for (index, constant) in constants.iter().enumerate() {
+        match constant {
+            Constant::Value(value) => {
+                bytes.push(0);
+                bytes.extend(value.encode().ok_or(ContainerError::InvalidConstant(index))?);
+            }
+
+            Constant::Bytes(blob) => {
+                bytes.push(1);
+                write_section(&mut bytes, blob);
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn read_debug(bytes: &[u8]) -> Result<DebugInfo, ContainerError> {
+    let mut reader = Reader { bytecode: bytes, offset: 0 };
+    let source = String::from_utf8(section(&mut reader)?.to_vec()).map_err(|_| ContainerError::InvalidDebugInfo)?;
+    let count = u32::from_be_bytes(reader.bytes()?) as usize;
+    let mut lines = Vec::new();
+
+    for _ in 0..count {
+        let offset = u32::from_be_bytes(reader.bytes()?) as usize;
+        let line = u32::from_be_bytes(reader.bytes()?) as usize;
+        lines.push(SourceLine { offset, line });
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(ContainerError::TrailingBytes);
+    }
+
+    Ok(DebugInfo { source, lines })
+}
+
+fn write_debug(debug: &DebugInfo) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_section(&mut bytes, debug.source.as_bytes());
+    bytes.extend((debug.lines.len() as u32).to_be_bytes());
+    for line in &debug.lines {
+        bytes.extend((line.offset as u32).to_be_bytes());
+        bytes.extend((line.line as u32).to_be_bytes());
+    }
+
+    bytes
+}
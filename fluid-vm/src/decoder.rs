@@ -0,0 +1,246 @@
+use crate::tools::*;
+
+/// operand decoded when bytecode is loaded, maximum indexes are read when instruction executes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Immediate(Immediate),
+    /// maximum index of input, type tag 11
+    InputMax,
+    /// maximum index of output, type tag 12
+    OutputMax,
+    /// index of constant of the program, type tag 13, decode replaces it with value of the constant
+    Constant(u16),
+}
+
+/// reason why bytecode could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// opcode has no instruction assigned
+    UnknownOpcode,
+    /// operand runs past the end of bytecode
+    TruncatedOperand,
+    /// type tag of immediate operand is not 0..=13
+    InvalidTypeTag(u8),
+    /// operand refers to a constant the program does not have
+    InvalidConstant(u16),
+}
+
+/// instruction with decoded operands, variants are named after opcodes in OPCODES
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Nop,
+    Push(Value),
+    Pop,
+    Popi(Value),
+    Set(Value, Value),
+    Get(Value),
+    Geti(Value, Value),
+    ClearI,
+    ClearO,
+    /// type_of_elements, length
+    Gen(u8, u64),
+    Save(Value),
+    Savei(Value, Value),
+    /// address, type_of_value
+    Load(u64, u8),
+    /// address, index, type_of_value
+    Loadi(u64, Value, u8),
+    Less,
+    Great,
+    Eq,
+    Jmp,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Neg,
+    Abs,
+    JmpIf,
+    JmpUnless,
+    Call(Value),
+    Ret(Value),
+    Halt,
+    Ovf(Value),
+    Cast(u8),
+    Castc(u8),
+    Store,
+    Free,
+    Aload,
+    Astore,
+    Alen,
+    /// bytecode that could not be decoded, fails when it is executed
+    Fault(Fault),
+}
+
+/// bytecode decoded into instructions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// decoded instructions, decoding stops after the first Fault
+    pub instructions: Vec<Instruction>,
+    /// byte offset of every instruction followed by length of bytecode
+    pub offsets: Vec<usize>,
+    /// index of instruction starting at every byte offset, usize::MAX inside of instructions
+    indexes: Vec<usize>,
+}
+
+impl Program {
+    /// returns index of instruction that starts at byte offset
+    pub fn index_of(&self, offset: usize) -> Option<usize> {
+        match self.indexes.get(offset) {
+            Some(&usize::MAX) => None,
+            Some(&index) => Some(index),
+            None if offset == self.indexes.len() => Some(self.instructions.len()),
+            None => None,
+        }
+    }
+}
+
+impl Instruction {
+    /// returns instruction with every immediate operand replaced by result of f
+    pub fn map_values(self, mut f: impl FnMut(Value) -> Result<Value, Fault>) -> Result<Self, Fault> {
+        let instruction = match self {
+            Instruction::Push(value) => Instruction::Push(f(value)?),
+            Instruction::Popi(index) => Instruction::Popi(f(index)?),
+            Instruction::Set(value, index) => Instruction::Set(f(value)?, f(index)?),
+            Instruction::Get(index) => Instruction::Get(f(index)?),
+            Instruction::Geti(o_index, i_index) => Instruction::Geti(f(o_index)?, f(i_index)?),
+            Instruction::Save(value) => Instruction::Save(f(value)?),
+            Instruction::Savei(value, index) => Instruction::Savei(f(value)?, f(index)?),
+            Instruction::Loadi(address, index, value_type) => Instruction::Loadi(address, f(index)?, value_type),
+            Instruction::Call(count) => Instruction::Call(f(count)?),
+            Instruction::Ret(count) => Instruction::Ret(f(count)?),
+            Instruction::Ovf(mode) => Instruction::Ovf(f(mode)?),
+            instruction => instruction,
+        };
+
+        Ok(instruction)
+    }
+}
+
+/// decodes every instruction of bytecode and replaces operands that refer to constants with their values
+pub fn decode(bytecode: &[u8], constants: &[Immediate]) -> Program {
+    let mut instructions = Vec::new();
+    let mut offsets = Vec::new();
+    let mut indexes = vec![usize::MAX; bytecode.len()];
+    let mut offset = 0;
+
+    while offset < bytecode.len() {
+        indexes[offset] = instructions.len();
+        offsets.push(offset);
+        let resolve = |value| match value {
+            Value::Constant(index) => constants.get(index as usize)
+                .map(|constant| Value::Immediate(*constant))
+                .ok_or(Fault::InvalidConstant(index)),
+            value => Ok(value),
+        };
+
+        match decode_at(bytecode, offset).and_then(|(instruction, next)| Ok((instruction.map_values(resolve)?, next))) {
+            Ok((instruction, next)) => {
+                instructions.push(instruction);
+                offset = next;
+            }
+
+            Err(fault) => {
+                instructions.push(Instruction::Fault(fault));
+                break;
+            }
+        }
+    }
+
+    offsets.push(bytecode.len());
+    Program { instructions, offsets, indexes }
+}
+
+/// decodes instruction at offset, returns it with offset of the next instruction
+pub fn decode_at(bytecode: &[u8], offset: usize) -> Result<(Instruction, usize), Fault> {
+    let mut reader = Reader { bytecode, offset };
+    let instruction = match reader.byte()? {
+        0 => Instruction::Nop,
+        1 => Instruction::Push(reader.value()?),
+        2 => Instruction::Pop,
+        3 => Instruction::Popi(reader.value()?),
+        4 => Instruction::Set(reader.value()?, reader.value()?),
+        5 => Instruction::Get(reader.value()?),
+        6 => Instruction::Geti(reader.value()?, reader.value()?),
+        7 => Instruction::ClearI,
+        8 => Instruction::ClearO,
+        9 => Instruction::Gen(reader.byte()?, reader.address()?),
+        10 => Instruction::Save(reader.value()?),
+        11 => Instruction::Savei(reader.value()?, reader.value()?),
+        12 => Instruction::Load(reader.address()?, reader.byte()?),
+        13 => Instruction::Loadi(reader.address()?, reader.value()?, reader.byte()?),
+        14 => Instruction::Less,
+        15 => Instruction::Great,
+        16 => Instruction::Eq,
+        17 => Instruction::Jmp,
+        18 => Instruction::Add,
+        19 => Instruction::Sub,
+        20 => Instruction::Mul,
+        21 => Instruction::Div,
+        22 => Instruction::Rem,
+        23 => Instruction::Neg,
+        24 => Instruction::Abs,
+        25 => Instruction::JmpIf,
+        26 => Instruction::JmpUnless,
+        27 => Instruction::Call(reader.value()?),
+        28 => Instruction::Ret(reader.value()?),
+        29 => Instruction::Halt,
+        30 => Instruction::Ovf(reader.value()?),
+        31 => Instruction::Cast(reader.byte()?),
+        32 => Instruction::Castc(reader.byte()?),
+        33 => Instruction::Store,
+        34 => Instruction::Free,
+        35 => Instruction::Aload,
+        36 => Instruction::Astore,
+        37 => Instruction::Alen,
+        _ => return Err(Fault::UnknownOpcode),
+    };
+
+    Ok((instruction, reader.offset))
+}
+
+/// reads operands from bytecode
+pub struct Reader<'a> {
+    pub bytecode: &'a [u8],
+    pub offset: usize,
+}
+
+impl Reader<'_> {
+    pub fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Fault> {
+        let bytes = self.bytecode.get(self.offset..self.offset + N).ok_or(Fault::TruncatedOperand)?;
+        self.offset += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn byte(&mut self) -> Result<u8, Fault> {
+        let [byte] = self.bytes::<1>()?;
+        Ok(byte)
+    }
+
+    pub fn address(&mut self) -> Result<u64, Fault> {
+        Ok(u64::from_be_bytes(self.bytes()?))
+    }
+
+    pub fn value(&mut self) -> Result<Value, Fault> {
+        let value = match self.byte()? {
+            0 => Immediate::U8(self.byte()?), // u8
+            1 => Immediate::U16(u16::from_be_bytes(self.bytes()?)), // u16
+            2 => Immediate::U32(u32::from_be_bytes(self.bytes()?)), // u32
+            3 => Immediate::U64(u64::from_be_bytes(self.bytes()?)), // u64
+            4 => Immediate::I8(self.byte()? as i8), // i8
+            5 => Immediate::I16(i16::from_be_bytes(self.bytes()?)), // i16
+            6 => Immediate::I32(i32::from_be_bytes(self.bytes()?)), // i32
+            7 => Immediate::I64(i64::from_be_bytes(self.bytes()?)), // i64
+            8 => Immediate::F32(f32::from_be_bytes(self.bytes()?)), // f32
+            9 => Immediate::F64(f64::from_be_bytes(self.bytes()?)), // f64
+            10 => Immediate::BOOL(self.byte()? != 0), // bool
+            11 => return Ok(Value::InputMax), // maximum index of input
+            12 => return Ok(Value::OutputMax), // maximum index of output
+            13 => return Ok(Value::Constant(u16::from_be_bytes(self.bytes()?))), // index of constant
+            tag => return Err(Fault::InvalidTypeTag(tag)),
+        };
+
+        Ok(Value::Immediate(value))
+    }
+}
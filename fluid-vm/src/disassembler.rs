@@ -0,0 +1,109 @@
+use std::fmt;
+
+use crate::decoder::*;
+use crate::opcodes::*;
+use crate::tools::*;
+use crate::verifier::*;
+
+/// instruction of bytecode rendered as text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassembledInstruction {
+    /// byte offset of instruction
+    pub offset: usize,
+    /// mnemonic with decoded operands, `.byte` for a byte that could not be decoded
+    pub text: String,
+    /// reason why byte could not be decoded
+    pub problem: Option<VerifyReason>,
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>5}: {}", self.offset, self.text)?;
+        if let Some(problem) = self.problem {
+            write!(f, "  ; {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// renders every instruction of bytecode, one per line
+///
+/// immediates are written as `U16(255)`, constants as `#3`, indexes of buffers as `input[2]` where a bare index
+/// is a u8, addresses as `addr=4` and lengths as `len=16`
+///
+/// a byte that can not be decoded is written as `.byte` with the reason and disassembling
+/// continues at the next byte, the text of every line can be assembled back into the same bytes
+pub fn disassemble(bytecode: &[u8]) -> Vec<DisassembledInstruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytecode.len() {
+        let (text, problem, next) = match decode_at(bytecode, offset) {
+            Ok((instruction, next)) => match operands(instruction) {
+                Ok(operands) => {
+                    let name = OPCODES[bytecode[offset] as usize].name;
+                    let text = if operands.is_empty() { name.to_string() } else { format!("{} {}", name, operands) };
+                    (text, None, next)
+                }
+                Err(tag) => (byte(bytecode[offset]), Some(VerifyReason::InvalidValueType(tag)), offset + 1),
+            },
+            Err(fault) => {
+                let problem = VerifyReason::from_fault(fault, bytecode[offset]);
+                (byte(bytecode[offset]), Some(problem), offset + 1)
+            }
+        };
+
+        instructions.push(DisassembledInstruction { offset, text, problem });
+        offset = next;
+    }
+
+    instructions
+}
+
+fn byte(value: u8) -> String {
+    format!(".byte 0x{:02x}", value)
+}
+
+/// returns operands of instruction, fails with type tag that has no name
+fn operands(instruction: Instruction) -> Result<String, u8> {
+    let text = match instruction {
+        Instruction::Push(value) | Instruction::Save(value) => immediate(value),
+        Instruction::Call(value) | Instruction::Ret(value) | Instruction::Ovf(value) => immediate(value),
+        Instruction::Popi(index) => format!("-> input[{}]", buffer_index(index)),
+        Instruction::Set(value, index) => format!("{} -> input[{}]", immediate(value), buffer_index(index)),
+        Instruction::Get(index) => format!("output[{}]", buffer_index(index)),
+        Instruction::Geti(o_index, i_index) => format!("output[{}] -> input[{}]", buffer_index(o_index), buffer_index(i_index)),
+        Instruction::Gen(element_type, length) => format!("{} len={}", type_name(element_type)?, length),
+        Instruction::Savei(value, index) => format!("{} -> output[{}]", immediate(value), buffer_index(index)),
+        Instruction::Load(address, value_type) => format!("addr={} as {}", address, type_name(value_type)?),
+        Instruction::Loadi(address, index, value_type) => {
+            format!("addr={} -> output[{}] as {}", address, buffer_index(index), type_name(value_type)?)
+        }
+        Instruction::Cast(value_type) | Instruction::Castc(value_type) => type_name(value_type)?.to_string(),
+        _ => String::new(),
+    };
+
+    Ok(text)
+}
+
+fn immediate(value: Value) -> String {
+    match value {
+        Value::Immediate(value) => format!("{:?}", value),
+        Value::InputMax => "input_max".to_string(),
+        Value::OutputMax => "output_max".to_string(),
+        Value::Constant(index) => format!("#{}", index),
+    }
+}
+
+/// u8 indexes are written without their type
+fn buffer_index(index: Value) -> String {
+    match index {
+        Value::Immediate(Immediate::U8(index)) => index.to_string(),
+        _ => immediate(index),
+    }
+}
+
+fn type_name(tag: u8) -> Result<&'static str, u8> {
+    TYPES.get(tag as usize).copied().ok_or(tag)
+}
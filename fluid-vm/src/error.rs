@@ -0,0 +1,131 @@
+use std::fmt;
+
+use crate::heap::HeapLimit;
+use crate::tools::*;
+
+/// error that stopped execution of bytecode
+///
+/// every variant carries ip and opcode of the instruction that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// opcode has no instruction assigned
+    UnknownOpcode { ip: usize, opcode: u8 },
+    /// operand of instruction runs past the end of bytecode
+    TruncatedOperand { ip: usize, opcode: u8 },
+    /// value was popped from an empty buffer
+    StackUnderflow { ip: usize, opcode: u8 },
+    /// address does not point to a value on heap
+    InvalidHeapAddress { ip: usize, opcode: u8, address: Address },
+    /// value has a different type than the instruction expects
+    TypeMismatch { ip: usize, opcode: u8 },
+    /// type tag of operand is not known
+    InvalidTypeTag { ip: usize, opcode: u8, tag: u8 },
+    /// call would nest deeper than the maximum call depth
+    CallDepthExceeded { ip: usize, opcode: u8, depth: usize },
+    /// return was executed outside of a called function
+    ReturnWithoutCall { ip: usize, opcode: u8 },
+    /// integer result does not fit into its type and overflow is trap
+    ArithmeticOverflow { ip: usize, opcode: u8 },
+    /// integer was divided by zero
+    DivisionByZero { ip: usize, opcode: u8 },
+    /// operand has a value the instruction does not accept
+    InvalidOperand { ip: usize, opcode: u8 },
+    /// checked cast of a value that does not fit into the target type
+    CastOutOfRange { ip: usize, opcode: u8 },
+    /// jump target is inside of an instruction or past the end of bytecode
+    InvalidJumpTarget { ip: usize, opcode: u8, target: usize },
+    /// operand refers to a constant the program does not have
+    InvalidConstant { ip: usize, opcode: u8, index: usize },
+    /// address was freed before
+    DoubleFree { ip: usize, opcode: u8, address: Address },
+    /// value at address was used after it was freed
+    UseAfterFree { ip: usize, opcode: u8, address: Address },
+    /// index is negative or not less than the length of the array
+    IndexOutOfBounds { ip: usize, opcode: u8, index: i128, length: usize },
+    /// allocation of size bytes would exceed a limit of heap
+    HeapLimitExceeded { ip: usize, opcode: u8, limit: HeapLimit, size: usize },
+    /// value would be set at index past the maximum length of input or output
+    BufferLimitExceeded { ip: usize, opcode: u8, index: usize, limit: usize },
+}
+
+impl VmError {
+    /// returns ip of the instruction that failed
+    pub fn ip(&self) -> usize {
+        match *self {
+            VmError::UnknownOpcode { ip, .. } => ip,
+            VmError::TruncatedOperand { ip, .. } => ip,
+            VmError::StackUnderflow { ip, .. } => ip,
+            VmError::InvalidHeapAddress { ip, .. } => ip,
+            VmError::TypeMismatch { ip, .. } => ip,
+            VmError::InvalidTypeTag { ip, .. } => ip,
+            VmError::CallDepthExceeded { ip, .. } => ip,
+            VmError::ReturnWithoutCall { ip, .. } => ip,
+            VmError::ArithmeticOverflow { ip, .. } => ip,
+            VmError::DivisionByZero { ip, .. } => ip,
+            VmError::InvalidOperand { ip, .. } => ip,
+            VmError::CastOutOfRange { ip, .. } => ip,
+            VmError::InvalidJumpTarget { ip, .. } => ip,
+            VmError::InvalidConstant { ip, .. } => ip,
+            VmError::DoubleFree { ip, .. } => ip,
+            VmError::UseAfterFree { ip, .. } => ip,
+            VmError::IndexOutOfBounds { ip, .. } => ip,
+            VmError::HeapLimitExceeded { ip, .. } => ip,
+            VmError::BufferLimitExceeded { ip, .. } => ip,
+        }
+    }
+
+    /// returns opcode of the instruction that failed
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            VmError::UnknownOpcode { opcode, .. } => opcode,
+            VmError::TruncatedOperand { opcode, .. } => opcode,
+            VmError::StackUnderflow { opcode, .. } => opcode,
+            VmError::InvalidHeapAddress { opcode, .. } => opcode,
+            VmError::TypeMismatch { opcode, .. } => opcode,
+            VmError::InvalidTypeTag { opcode, .. } => opcode,
+            VmError::CallDepthExceeded { opcode, .. } => opcode,
+            VmError::ReturnWithoutCall { opcode, .. } => opcode,
+            VmError::ArithmeticOverflow { opcode, .. } => opcode,
+            VmError::DivisionByZero { opcode, .. } => opcode,
+            VmError::InvalidOperand { opcode, .. } => opcode,
+            VmError::CastOutOfRange { opcode, .. } => opcode,
+            VmError::InvalidJumpTarget { opcode, .. } => opcode,
+            VmError::InvalidConstant { opcode, .. } => opcode,
+            VmError::DoubleFree { opcode, .. } => opcode,
+            VmError::UseAfterFree { opcode, .. } => opcode,
+            VmError::IndexOutOfBounds { opcode, .. } => opcode,
+            VmError::HeapLimitExceeded { opcode, .. } => opcode,
+            VmError::BufferLimitExceeded { opcode, .. } => opcode,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            VmError::UnknownOpcode { .. } => write!(f, "unknown opcode")?,
+            VmError::TruncatedOperand { .. } => write!(f, "operand runs past the end of bytecode")?,
+            VmError::StackUnderflow { .. } => write!(f, "pop from an empty buffer")?,
+            VmError::InvalidHeapAddress { address, .. } => write!(f, "invalid heap address {}", address)?,
+            VmError::TypeMismatch { .. } => write!(f, "type mismatch")?,
+            VmError::InvalidTypeTag { tag, .. } => write!(f, "invalid type tag {}", tag)?,
+            VmError::CallDepthExceeded { depth, .. } => write!(f, "maximum call depth {} exceeded", depth)?,
+            VmError::ReturnWithoutCall { .. } => write!(f, "return outside of a call")?,
+            VmError::ArithmeticOverflow { .. } => write!(f, "arithmetic overflow")?,
+            VmError::DivisionByZero { .. } => write!(f, "division by zero")?,
+            VmError::InvalidOperand { .. } => write!(f, "invalid operand")?,
+            VmError::CastOutOfRange { .. } => write!(f, "value does not fit into type of cast")?,
+            VmError::InvalidJumpTarget { target, .. } => write!(f, "jump target {} is not the start of an instruction", target)?,
+            VmError::InvalidConstant { index, .. } => write!(f, "constant #{} does not exist", index)?,
+            VmError::DoubleFree { address, .. } => write!(f, "heap address {} was already freed", address)?,
+            VmError::UseAfterFree { address, .. } => write!(f, "heap address {} was used after it was freed", address)?,
+            VmError::IndexOutOfBounds { index, length, .. } => write!(f, "index {} is out of bounds of array of length {}", index, length)?,
+            VmError::HeapLimitExceeded { limit, size, .. } => write!(f, "allocation of {} bytes exceeds limit of {}", size, limit)?,
+            VmError::BufferLimitExceeded { index, limit, .. } => write!(f, "index {} exceeds buffer limit of {} values", index, limit)?,
+        }
+
+        write!(f, " (opcode {} at ip {})", self.opcode(), self.ip())
+    }
+}
+
+impl std::error::Error for VmError {}
@@ -0,0 +1,11 @@
+use crate::buffer::*;
+
+/// saved state of a caller while a called function runs
+pub struct Frame {
+    /// index of instruction to continue from after return
+    pub return_pc: usize,
+    /// input of the caller
+    pub input: Buffer,
+    /// output of the caller
+    pub output: Buffer,
+}
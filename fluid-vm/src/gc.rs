@@ -0,0 +1,62 @@
+use crate::heap::*;
+use crate::tools::*;
+
+/// statistics of garbage collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GcStats {
+    /// number of collections that ran
+    pub collections: u64,
+    /// objects freed by all collections
+    pub freed: u64,
+    /// objects that were live after the last collection
+    pub live: usize,
+}
+
+/// state of garbage collection of a VM
+#[derive(Debug, Clone, Default)]
+pub struct Gc {
+    /// number of allocations after which garbage is collected, None if it is only collected on request
+    pub threshold: Option<usize>,
+    /// allocations since the last collection
+    pub allocations: usize,
+    pub stats: GcStats,
+}
+
+impl Gc {
+    /// returns true if garbage has to be collected before the next allocation
+    pub fn due(&self) -> bool {
+        self.threshold.is_some_and(|threshold| self.allocations >= threshold)
+    }
+
+    /// records collection that freed objects and left live objects on heap
+    pub fn record(&mut self, freed: usize, live: usize) {
+        self.allocations = 0;
+        self.stats.collections += 1;
+        self.stats.freed += freed as u64;
+        self.stats.live = live;
+    }
+}
+
+/// marks objects reachable from addresses of roots and deletes every other object from heap,
+/// returns number of deleted objects
+pub fn collect(heap: &mut Heap, roots: impl Iterator<Item = Immediate>) -> usize {
+    let mut marked = vec![false; heap.slots()];
+    let mut pending: Vec<Address> = roots
+        .filter_map(|value| match value {
+            Immediate::ADDRESS(address) => Some(address),
+            _ => None,
+        })
+        .collect();
+
+    while let Some(address) = pending.pop() {
+        match heap.get(address) {
+            Ok(object) if !marked[address.index as usize] => {
+                marked[address.index as usize] = true;
+                object.references(&mut pending);
+            }
+            _ => {}
+        }
+    }
+
+    heap.sweep(&marked)
+}
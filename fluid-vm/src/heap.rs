@@ -1,9 +1,351 @@
-use crate::allocator::{ Ptr, Allocation };
+use std::fmt;
+use std::mem;
+
+use crate::opcodes::immediate_size;
 use crate::tools::*;
 
+/// default of HeapLimits::max_bytes
+pub const DEFAULT_MAX_HEAP_BYTES: usize = 1 << 30;
+
+/// default of HeapLimits::max_objects
+pub const DEFAULT_MAX_HEAP_OBJECTS: usize = 1 << 24;
+
+/// default of HeapLimits::max_allocation
+pub const DEFAULT_MAX_ALLOCATION: usize = 1 << 28;
+
+/// limits of heap of a VM, allocation that would exceed one of them fails with HeapLimitExceeded
+///
+/// sizes are bytes of values and elements without bookkeeping of heap, blobs of constants count
+/// towards limits but are added even if they exceed them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapLimits {
+    /// bytes of all live objects
+    pub max_bytes: usize,
+    /// number of live objects
+    pub max_objects: usize,
+    /// bytes of a single object
+    pub max_allocation: usize,
+}
+
+impl Default for HeapLimits {
+    fn default() -> Self {
+        HeapLimits {
+            max_bytes: DEFAULT_MAX_HEAP_BYTES,
+            max_objects: DEFAULT_MAX_HEAP_OBJECTS,
+            max_allocation: DEFAULT_MAX_ALLOCATION,
+        }
+    }
+}
+
+/// limit of heap that an allocation exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapLimit {
+    Bytes,
+    Objects,
+    Allocation,
+}
+
+impl fmt::Display for HeapLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeapLimit::Bytes => write!(f, "total heap bytes"),
+            HeapLimit::Objects => write!(f, "live heap objects"),
+            HeapLimit::Allocation => write!(f, "size of a single allocation"),
+        }
+    }
+}
+
+/// returns type tag of value, addresses have the tag of u64
+pub fn value_tag(value: Immediate) -> Option<u8> {
+    let tag = match value {
+        Immediate::U8(_) => 0,
+        Immediate::U16(_) => 1,
+        Immediate::U32(_) => 2,
+        Immediate::U64(_) | Immediate::ADDRESS(_) => 3,
+        Immediate::I8(_) => 4,
+        Immediate::I16(_) => 5,
+        Immediate::I32(_) => 6,
+        Immediate::I64(_) => 7,
+        Immediate::F32(_) => 8,
+        Immediate::F64(_) => 9,
+        Immediate::BOOL(_) => 10,
+        Immediate::NONE() => return None,
+    };
+
+    Some(tag)
+}
+
+/// returns size of value of type tag, None if tag is not a type of values
+pub fn type_size(tag: u8) -> Option<usize> {
+    immediate_size(tag).filter(|_| tag <= 10)
+}
+
+/// array generated by gen, elements have the type of its variant
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Array {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+    /// elements and which of them were stored as an address, empty until an address is stored
+    U64(Vec<u64>, Vec<bool>),
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+    BOOL(Vec<bool>),
+}
+
+impl Array {
+    /// returns array of length with zeroed elements of type tag, None if tag is unknown
+    pub fn new(element_type: u8, length: usize) -> Option<Self> {
+        let array = match element_type {
+            0 => Array::U8(vec![0; length]),
+            1 => Array::U16(vec![0; length]),
+            2 => Array::U32(vec![0; length]),
+            3 => Array::U64(vec![0; length], Vec::new()),
+            4 => Array::I8(vec![0; length]),
+            5 => Array::I16(vec![0; length]),
+            6 => Array::I32(vec![0; length]),
+            7 => Array::I64(vec![0; length]),
+            8 => Array::F32(vec![0.0; length]),
+            9 => Array::F64(vec![0.0; length]),
+            10 => Array::BOOL(vec![false; length]),
+            _ => return None,
+        };
+
+        Some(array)
+    }
+
+    /// returns type tag of elements
+    pub fn element_type(&self) -> u8 {
+        match self {
+            Array::U8(_) => 0,
+            Array::U16(_) => 1,
+            Array::U32(_) => 2,
+            Array::U64(..) => 3,
+            Array::I8(_) => 4,
+            Array::I16(_) => 5,
+            Array::I32(_) => 6,
+            Array::I64(_) => 7,
+            Array::F32(_) => 8,
+            Array::F64(_) => 9,
+            Array::BOOL(_) => 10,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Array::U8(data) => data.len(),
+            Array::U16(data) => data.len(),
+            Array::U32(data) => data.len(),
+            Array::U64(data, _) => data.len(),
+            Array::I8(data) => data.len(),
+            Array::I16(data) => data.len(),
+            Array::I32(data) => data.len(),
+            Array::I64(data) => data.len(),
+            Array::F32(data) => data.len(),
+            Array::F64(data) => data.len(),
+            Array::BOOL(data) => data.len(),
+        }
+    }
+
+    /// returns element at index, panics if index is out of bounds
+    pub fn get(&self, index: usize) -> Immediate {
+        match self {
+            Array::U8(data) => Immediate::U8(data[index]),
+            Array::U16(data) => Immediate::U16(data[index]),
+            Array::U32(data) => Immediate::U32(data[index]),
+            Array::U64(data, _) => Immediate::U64(data[index]),
+            Array::I8(data) => Immediate::I8(data[index]),
+            Array::I16(data) => Immediate::I16(data[index]),
+            Array::I32(data) => Immediate::I32(data[index]),
+            Array::I64(data) => Immediate::I64(data[index]),
+            Array::F32(data) => Immediate::F32(data[index]),
+            Array::F64(data) => Immediate::F64(data[index]),
+            Array::BOOL(data) => Immediate::BOOL(data[index]),
+        }
+    }
+
+    /// sets element at index and returns the element it replaced, None if value has another type than
+    /// elements, addresses are stored in arrays of u64 and are replaced as addresses, panics if index is
+    /// out of bounds
+    pub fn set(&mut self, index: usize, value: Immediate) -> Option<Immediate> {
+        let replaced = match (self, value) {
+            (Array::U8(data), Immediate::U8(v)) => Immediate::U8(mem::replace(&mut data[index], v)),
+            (Array::U16(data), Immediate::U16(v)) => Immediate::U16(mem::replace(&mut data[index], v)),
+            (Array::U32(data), Immediate::U32(v)) => Immediate::U32(mem::replace(&mut data[index], v)),
+            (Array::U64(data, addresses), Immediate::U64(v)) => set_u64(data, addresses, index, v, false),
+            (Array::U64(data, addresses), Immediate::ADDRESS(v)) => set_u64(data, addresses, index, v.to_u64(), true),
+            (Array::I8(data), Immediate::I8(v)) => Immediate::I8(mem::replace(&mut data[index], v)),
+            (Array::I16(data), Immediate::I16(v)) => Immediate::I16(mem::replace(&mut data[index], v)),
+            (Array::I32(data), Immediate::I32(v)) => Immediate::I32(mem::replace(&mut data[index], v)),
+            (Array::I64(data), Immediate::I64(v)) => Immediate::I64(mem::replace(&mut data[index], v)),
+            (Array::F32(data), Immediate::F32(v)) => Immediate::F32(mem::replace(&mut data[index], v)),
+            (Array::F64(data), Immediate::F64(v)) => Immediate::F64(mem::replace(&mut data[index], v)),
+            (Array::BOOL(data), Immediate::BOOL(v)) => Immediate::BOOL(mem::replace(&mut data[index], v)),
+            _ => return None,
+        };
+
+        Some(replaced)
+    }
+
+    /// returns index and address of every element that was stored as an address
+    pub fn addresses(&self) -> impl Iterator<Item = (usize, Address)> + '_ {
+        let (data, addresses) = match self {
+            Array::U64(data, addresses) => (data.as_slice(), addresses.as_slice()),
+            _ => (&[][..], &[][..]),
+        };
+        addresses.iter().enumerate()
+            .filter(|(_, &address)| address)
+            .map(|(index, _)| (index, Address::from_u64(data[index])))
+    }
+}
+
+/// sets element of array of u64 and marks whether it is an address, returns the element it replaced
+fn set_u64(data: &mut [u64], addresses: &mut Vec<bool>, index: usize, value: u64, address: bool) -> Immediate {
+    if address && addresses.is_empty() {
+        addresses.resize(data.len(), false);
+    }
+
+    let was_address = addresses.get_mut(index).is_some_and(|marked| mem::replace(marked, address));
+    let replaced = mem::replace(&mut data[index], value);
+    if was_address { Immediate::ADDRESS(Address::from_u64(replaced)) } else { Immediate::U64(replaced) }
+}
+
+/// object stored on heap
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapObject {
+    /// value saved by save or savei, addresses keep their type so they can be followed by the collector
+    Scalar(Immediate),
+    /// array generated by gen
+    Array(Array),
+    /// blob of a constant, it is accessed like an array of u8
+    Bytes(Vec<u8>),
+}
+
+impl HeapObject {
+    /// returns bytes of value or elements
+    pub fn size(&self) -> usize {
+        match self {
+            HeapObject::Scalar(value) => value_tag(*value).and_then(type_size).unwrap_or(0),
+            HeapObject::Array(array) => array.len() * type_size(array.element_type()).unwrap_or(0),
+            HeapObject::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    /// returns type tag of value or elements, blobs have the tag of u8
+    pub fn tag(&self) -> u8 {
+        match self {
+            HeapObject::Scalar(value) => value_tag(*value).unwrap_or(0),
+            HeapObject::Array(array) => array.element_type(),
+            HeapObject::Bytes(_) => 0,
+        }
+    }
+
+    /// returns type tag and length of elements, None for a scalar
+    pub fn elements(&self) -> Option<(u8, usize)> {
+        match self {
+            HeapObject::Scalar(_) => None,
+            HeapObject::Array(array) => Some((array.element_type(), array.len())),
+            HeapObject::Bytes(bytes) => Some((0, bytes.len())),
+        }
+    }
+
+    /// returns element at index, panics if object is a scalar or index is out of bounds
+    pub fn element(&self, index: usize) -> Immediate {
+        match self {
+            HeapObject::Array(array) => array.get(index),
+            HeapObject::Bytes(bytes) => Immediate::U8(bytes[index]),
+            HeapObject::Scalar(_) => panic!("scalar has no elements"),
+        }
+    }
+
+    /// pushes addresses this object refers to, elements of arrays of u64 are pushed as well
+    /// because addresses are stored in them as u64
+    pub fn references(&self, addresses: &mut Vec<Address>) {
+        match self {
+            HeapObject::Scalar(Immediate::ADDRESS(address)) => addresses.push(*address),
+            HeapObject::Array(Array::U64(data, _)) => addresses.extend(data.iter().map(|&v| Address::from_u64(v))),
+            _ => {}
+        }
+    }
+
+    /// sets element at index and returns the element it replaced, None if object is a scalar or value
+    /// has another type than elements, panics if index is out of bounds
+    pub fn set_element(&mut self, index: usize, value: Immediate) -> Option<Immediate> {
+        match (self, value) {
+            (HeapObject::Array(array), value) => array.set(index, value),
+            (HeapObject::Bytes(bytes), Immediate::U8(v)) => Some(Immediate::U8(mem::replace(&mut bytes[index], v))),
+            _ => None,
+        }
+    }
+}
+
+/// reason why address can not be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// address was never allocated
+    Invalid,
+    /// object at address was deleted, its slot may hold another object of a later generation
+    Freed,
+}
+
+/// live object on heap as it is seen by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapObjectInfo {
+    pub address: Address,
+    /// type tag of value or elements, blobs of constants have the tag of u8
+    pub tag: u8,
+    /// number of elements, None for a value saved by save or savei
+    pub length: Option<usize>,
+    /// bytes of value or elements
+    pub size: usize,
+    /// ip of the instruction that allocated object, None for blobs of constants
+    pub ip: Option<usize>,
+}
+
+/// statistics of heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeapStats {
+    /// number of live objects
+    pub objects: usize,
+    /// bytes of live objects
+    pub bytes: usize,
+    /// most bytes that were live at once
+    pub peak_bytes: usize,
+    /// slots of live and freed objects
+    pub slots: usize,
+    /// slots of freed objects that are reused by the next allocations
+    pub free_slots: usize,
+    /// objects that were added
+    pub allocated: u64,
+    /// objects that were deleted
+    pub freed: u64,
+}
+
+/// slot of heap with generation and references of its object
+pub struct Slot {
+    pub generation: u32,
+    /// references that were counted, only kept up to date in reference counting mode
+    pub count: u32,
+    /// ip of the instruction that allocated object
+    pub ip: Option<usize>,
+    pub object: Option<HeapObject>,
+}
+
 pub struct Heap {
-    data: Vec<Ptr>,
-    empty: Vec<usize>,
+    data: Vec<Slot>,
+    empty: Vec<u32>,
+    /// number of live objects
+    objects: usize,
+    /// bytes of live objects
+    bytes: usize,
+    peak_bytes: usize,
+    allocated: u64,
+    freed: u64,
 }
 
 impl Heap {
@@ -11,36 +353,189 @@ impl Heap {
         Self {
             data: Vec::new(),
             empty: Vec::new(),
+            objects: 0,
+            bytes: 0,
+            peak_bytes: 0,
+            allocated: 0,
+            freed: 0,
+        }
+    }
+
+    /// returns heap of slots whose empty slots are reused in reverse order of empty,
+    /// None if an index of empty is out of bounds, repeated or refers to a slot that holds an object
+    pub fn from_slots(data: Vec<Slot>, empty: Vec<u32>, peak_bytes: usize, allocated: u64, freed: u64) -> Option<Self> {
+        let mut reused = vec![false; data.len()];
+        for &index in &empty {
+            let slot = data.get(index as usize)?;
+            if slot.object.is_some() || slot.generation == u32::MAX || mem::replace(&mut reused[index as usize], true) {
+                return None;
+            }
+        }
+
+        let objects = data.iter().filter(|slot| slot.object.is_some()).count();
+        let bytes = data.iter().filter_map(|slot| slot.object.as_ref()).map(HeapObject::size).sum();
+        Some(Self {
+            data,
+            empty,
+            objects,
+            bytes,
+            peak_bytes: peak_bytes.max(bytes),
+            allocated,
+            freed,
+        })
+    }
+
+    /// returns slots in order of their indexes
+    pub fn slot_data(&self) -> &[Slot] {
+        &self.data
+    }
+
+    /// returns indexes of empty slots, the last one is reused first
+    pub fn empty(&self) -> &[u32] {
+        &self.empty
+    }
+
+    /// returns number of live objects
+    pub fn objects(&self) -> usize {
+        self.objects
+    }
+
+    /// returns bytes of live objects
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// returns statistics of heap
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            objects: self.objects,
+            bytes: self.bytes,
+            peak_bytes: self.peak_bytes,
+            slots: self.data.len(),
+            free_slots: self.empty.len(),
+            allocated: self.allocated,
+            freed: self.freed,
+        }
+    }
+
+    /// adds object that was allocated by instruction at ip to heap,
+    /// slots of deleted objects are reused with the next generation
+    pub fn add(&mut self, object: HeapObject, ip: Option<usize>) -> Address {
+        self.objects += 1;
+        self.bytes += object.size();
+        self.peak_bytes = self.peak_bytes.max(self.bytes);
+        self.allocated += 1;
+        match self.empty.pop() {
+            Some(index) => {
+                let slot = &mut self.data[index as usize];
+                slot.count = 1;
+                slot.ip = ip;
+                slot.object = Some(object);
+                Address::new(index, slot.generation)
+            }
+            None => {
+                self.data.push(Slot { generation: 0, count: 1, ip, object: Some(object) });
+                Address::new(self.data.len() as u32 - 1, 0)
+            }
         }
     }
 
-    /// adds pointer to heap
-    pub fn add(&mut self, ptr: Ptr) -> Address {
-        let len = self.empty.len();
-        if len == 0 {
-            self.data.push(ptr);
-            self.data.len() - 1
-        } else {
-            let address = self.empty.pop().unwrap();
-            self.data[address] = ptr;
-            address
+    /// returns object at address
+    pub fn get(&self, address: Address) -> Result<&HeapObject, HeapError> {
+        let slot = self.data.get(address.index as usize).ok_or(HeapError::Invalid)?;
+        match &slot.object {
+            Some(object) if slot.generation == address.generation => Ok(object),
+            _ if slot.generation < address.generation => Err(HeapError::Invalid),
+            _ => Err(HeapError::Freed),
+        }
+    }
+
+    /// returns object at address to change it
+    pub fn get_mut(&mut self, address: Address) -> Result<&mut HeapObject, HeapError> {
+        let slot = self.data.get_mut(address.index as usize).ok_or(HeapError::Invalid)?;
+        match &mut slot.object {
+            Some(object) if slot.generation == address.generation => Ok(object),
+            _ if slot.generation < address.generation => Err(HeapError::Invalid),
+            _ => Err(HeapError::Freed),
+        }
+    }
+
+    /// returns addresses that hold an object
+    pub fn live(&self) -> impl Iterator<Item = Address> + '_ {
+        self.data.iter().enumerate()
+            .filter(|(_, slot)| slot.object.is_some())
+            .map(|(index, slot)| Address::new(index as u32, slot.generation))
+    }
+
+    /// returns live objects in order of their addresses
+    pub fn entries(&self) -> impl Iterator<Item = HeapObjectInfo> + '_ {
+        self.data.iter().enumerate().filter_map(|(index, slot)| {
+            let object = slot.object.as_ref()?;
+            Some(HeapObjectInfo {
+                address: Address::new(index as u32, slot.generation),
+                tag: object.tag(),
+                length: object.elements().map(|(_, length)| length),
+                size: object.size(),
+                ip: slot.ip,
+            })
+        })
+    }
+
+    /// returns number of slots, every index of an address is less than it
+    pub fn slots(&self) -> usize {
+        self.data.len()
+    }
+
+    /// deletes every object whose index is not marked, returns number of deleted objects
+    pub fn sweep(&mut self, marked: &[bool]) -> usize {
+        let mut deleted = 0;
+        for (index, &marked) in marked.iter().enumerate() {
+            if self.data[index].object.is_some() && !marked {
+                self.vacate(index);
+                deleted += 1;
+            }
         }
+
+        deleted
+    }
+
+    /// deletes object at address from heap and returns it
+    pub fn delete(&mut self, address: Address) -> Result<HeapObject, HeapError> {
+        self.get(address)?;
+        Ok(self.vacate(address.index as usize))
     }
 
-    /// returns pointer to value at address
-    pub fn get(&self, index: Address) -> Ptr {
-        self.data[index]
+    /// counts another reference to object at address, stale addresses are ignored
+    pub fn retain(&mut self, address: Address) {
+        if self.get(address).is_ok() {
+            let slot = &mut self.data[address.index as usize];
+            slot.count = slot.count.saturating_add(1);
+        }
     }
 
-    /// removes pointer from heap but won't deallocate it
-    pub fn remove(&mut self, index: Address) -> Ptr {
-        self.empty.push(index);
-        self.data[index]
-    } 
+    /// removes a reference to object at address, returns true if it was the last one,
+    /// stale addresses are ignored
+    pub fn release(&mut self, address: Address) -> bool {
+        if self.get(address).is_err() {
+            return false;
+        }
 
-    /// deallocates and deletes pointer from heap
-    pub fn delete(&mut self, index: Address, size: usize) {
-        self.empty.push(index);
-        self.data[index].deallocate(size);
+        let slot = &mut self.data[address.index as usize];
+        slot.count = slot.count.saturating_sub(1);
+        slot.count == 0
     }
-}
\ No newline at end of file
+
+    /// empties slot and moves it to the next generation, a slot whose generations ran out is not reused
+    fn vacate(&mut self, index: usize) -> HeapObject {
+        let slot = &mut self.data[index];
+        let object = slot.object.take().expect("slot holds an object");
+        self.objects -= 1;
+        self.bytes -= object.size();
+        self.freed += 1;
+        slot.generation += 1;
+        if slot.generation != u32::MAX {
+            self.empty.push(index as u32);
+        }
+        object
+    }
+}
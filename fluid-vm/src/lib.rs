@@ -1,830 +1,1192 @@
 mod tools;
-mod allocator;
 mod heap;
 mod buffer;
-
-use std::{ mem, fmt::Display, marker::PhantomData, fmt::Debug };
-use tools::*;
-use allocator::*;
+mod error;
+mod frame;
+mod decoder;
+mod opcodes;
+mod verifier;
+mod disassembler;
+mod container;
+mod gc;
+mod snapshot;
+#[cfg(test)]
+mod tests;
+
+use std::mem;
+use std::path::Path;
+pub use tools::*;
+pub use error::*;
+pub use opcodes::*;
+pub use verifier::*;
+pub use disassembler::*;
+pub use container::*;
+pub use snapshot::*;
+pub use gc::GcStats;
+pub use heap::{ HeapLimits, HeapLimit, HeapStats, HeapObjectInfo, DEFAULT_MAX_HEAP_BYTES, DEFAULT_MAX_HEAP_OBJECTS, DEFAULT_MAX_ALLOCATION };
 use heap::*;
 use buffer::*;
+use frame::*;
+use decoder::*;
+use gc::*;
+
+/// reason why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    /// ip ran off the end of bytecode
+    Finished,
+    /// halt was executed, holds exit value popped from input if there was one
+    Halted(Option<Immediate>),
+    /// fuel ran out before the next instruction, execution can be resumed from ip
+    OutOfFuel,
+    /// step or run_until stopped before the next instruction, execution can be resumed from ip
+    Paused,
+}
+
+/// state of the VM after execution stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExitState {
+    pub status: ExitStatus,
+    /// ip at which execution stopped
+    pub ip: usize,
+    /// number of instructions executed since the VM was created or cleared
+    pub instructions: u64,
+}
+
+/// default maximum number of nested calls
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// default maximum number of values set can grow input or output to
+pub const MAX_BUFFER_LEN: usize = 1 << 20;
+
+/// called with objects that were never freed
+type LeakReport = Box<dyn FnMut(&[HeapObjectInfo])>;
 
 pub struct VM {
-    ip: usize,
+    pc: usize,
     input: Buffer,
     output: Buffer,
     heap: Heap,
-    instructions: Vec<fn(&mut VM)>,
+    frames: Vec<Frame>,
+    max_call_depth: usize,
+    max_buffer_len: usize,
+    overflow: Overflow,
+    program: Program,
     bytecode: Vec<u8>,
-    jmp: bool,
+    constants: Vec<Constant>,
+    constant_values: Vec<Immediate>,
+    gc: Gc,
+    limits: HeapLimits,
+    reference_counting: bool,
+    released: Vec<Address>,
+    leak_report: Option<LeakReport>,
+    halt: Option<Option<Immediate>>,
+    executed: u64,
+    op_pc: usize,
 }
 
 impl VM {
+    /// decodes bytecode, bytecode that can not be decoded fails once it is executed
     pub fn new(bytecode: Vec<u8>) -> Self {
-        let mut vm = VM {
-            ip: 0,
+        VM::from_container(Container::new(bytecode))
+    }
+
+    /// returns VM of bytecode in container with default limits of heap, blobs of constants are added to heap
+    /// and operands that refer to constants are replaced by their values
+    pub fn from_container(container: Container) -> Self {
+        VM::with_limits(container, HeapLimits::default())
+    }
+
+    /// returns VM of bytecode in container whose heap can not grow beyond limits
+    pub fn with_limits(container: Container, limits: HeapLimits) -> Self {
+        let mut heap = Heap::new();
+        let values = add_constants(&mut heap, &container.constants);
+        VM {
+            pc: 0,
             input: Buffer::new(),
             output: Buffer::new(),
-            heap: Heap::new(),
-            instructions: Vec::new(),
-            bytecode,
-            jmp: false,
-        };
+            heap,
+            frames: Vec::new(),
+            max_call_depth: MAX_CALL_DEPTH,
+            max_buffer_len: MAX_BUFFER_LEN,
+            overflow: Overflow::Trap,
+            program: decode(&container.code, &values),
+            bytecode: container.code,
+            constants: container.constants,
+            constant_values: values,
+            gc: Gc::default(),
+            limits,
+            leak_report: None,
+            reference_counting: false,
+            released: Vec::new(),
+            halt: None,
+            executed: 0,
+            op_pc: 0,
+        }
+    }
 
-        vm.generate_instructions();
-        vm
+    /// reads .fluidc container from file, fails if it was built for another format or ISA version
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ContainerError> {
+        Ok(VM::from_container(Container::read_file(path)?))
     }
 
-    pub fn execute(&mut self) {
-        while self.ip < self.bytecode.len() {
-            let instruction = self.bytecode[self.ip];
-            self.execute_instruction(instruction);
-            if !self.jmp { self.ip += 1; }
-            else { self.jmp = false; }
+    /// executes bytecode until it runs off the end, halts or an instruction fails
+    pub fn execute(&mut self) -> Result<ExitState, VmError> {
+        self.run(None, None)
+    }
+
+    /// executes at most fuel instructions, stops with OutOfFuel if bytecode has not finished,
+    /// calling execute or execute_with_fuel again continues from where it stopped
+    pub fn execute_with_fuel(&mut self, fuel: u64) -> Result<ExitState, VmError> {
+        self.run(Some(fuel), None)
+    }
+
+    /// executes exactly one instruction, stops with Paused if bytecode has not finished
+    pub fn step(&mut self) -> Result<ExitState, VmError> {
+        if self.pc >= self.program.instructions.len() {
+            return Ok(self.exit_state(ExitStatus::Finished));
         }
+
+        if let Some(state) = self.step_instruction()? {
+            return Ok(state);
+        }
+
+        if self.pc >= self.program.instructions.len() {
+            return Ok(self.exit_state(ExitStatus::Finished));
+        }
+
+        Ok(self.exit_state(ExitStatus::Paused))
     }
 
-    pub fn clear(&mut self) {
-        self.input = Buffer::new();
-        self.output = Buffer::new();
-        self.ip = 0;
-        self.heap = Heap::new();
-        self.jmp = false;
+    /// executes instructions until ip reaches target and stops with Paused,
+    /// executes at least one instruction so it can be called again at the same target
+    pub fn run_until(&mut self, ip: usize) -> Result<ExitState, VmError> {
+        self.run(None, Some(ip))
     }
 
-    fn execute_instruction(&mut self, instruction: u8) {
-        if (instruction as usize) < self.instructions.len() {
-            self.instructions[instruction as usize](self);
-        } else {
-            panic!("Index of instruction out of bounds");
-        }
-    }
-
-    fn generate_instructions(&mut self)  {
-        self.instructions = vec![
-            VM::nop,        // 0
-            VM::push,       // 1
-            VM::pop,        // 2
-            VM::popi,       // 3
-            VM::set,        // 4
-            VM::get,        // 5
-            VM::geti,       // 6
-            VM::clear_i,    // 7
-            VM::clear_o,    // 8
-            VM::gen,        // 9
-            VM::save,       // 10
-            VM::savei,      // 11
-            VM::load,       // 12
-            VM::loadi,      // 13
-            VM::less,       // 14
-            VM::great,      // 15
-            VM::eq,         // 16
-            VM::jmp,        // 17
-        ];
-    }
-
-    /// does nothing
-    fn nop(&mut self) {    
-    }
-
-    /// args: type, value 
-    /// 
-    /// pushes value to input
-    fn push(&mut self) {
-        let value = self.get_immediate();
-        self.input.push(value);
+    /// returns ip of the next instruction
+    pub fn ip(&self) -> usize {
+        self.program.offsets[self.pc]
     }
 
-    /// pops value from output and pushes it to input
-    fn pop(&mut self) {
-        let value = self.output.pop();
-        self.input.push(value);
+    /// returns values of input
+    pub fn input(&self) -> &[Immediate] {
+        self.input.as_slice()
     }
 
-    /// args: type, index
-    /// 
-    /// pops value from output and sets it to input at index 
-    fn popi(&mut self) {
-        let value = self.output.pop();
-        let index = self.get_index();
-        self.input.set(index, value);
+    /// returns values of output
+    pub fn output(&self) -> &[Immediate] {
+        self.output.as_slice()
     }
 
-    /// args: type, value, type, index
-    /// 
-    /// sets value of input at index
-    fn set(&mut self) {
-        let value = self.get_immediate();
-        let index = self.get_index();
-        self.input.set(index, value);
+    /// returns addresses of values on heap that were not removed
+    pub fn heap_entries(&self) -> impl Iterator<Item = Address> + '_ {
+        self.heap.live()
     }
 
-    /// args: type, index
-    /// 
-    /// pushes value of output to index
-    fn get(&mut self) {
-        let index = self.get_index();
-        self.input.push(self.output.get(index))
+    /// returns live objects on heap with their types, sizes and the ip that allocated them
+    pub fn heap_objects(&self) -> impl Iterator<Item = HeapObjectInfo> + '_ {
+        self.heap.entries()
     }
 
-    /// args: type, output_index, type, input_index
-    /// 
-    /// gets value of output at index and sets it to input at index
-    fn geti(&mut self) {
-        let o_index = self.get_index();
-        let i_index = self.get_index();
-        let value = self.output.get(o_index);
-        self.input.set(i_index, value);
+    /// returns objects allocated by instructions that were not freed yet, blobs of constants are not included
+    pub fn leaks(&self) -> impl Iterator<Item = HeapObjectInfo> + '_ {
+        self.heap.entries().filter(|object| object.ip.is_some())
     }
 
-    /// clears input
-    fn clear_i(&mut self) {
-        self.input.clear();
+    /// calls report with objects that were never freed when the VM is cleared or dropped,
+    /// report is not called if there are none
+    pub fn set_leak_report(&mut self, report: impl FnMut(&[HeapObjectInfo]) + 'static) {
+        self.leak_report = Some(Box::new(report));
     }
 
-    /// clears output
-    fn clear_o(&mut self) {
-        self.output.clear();
+    /// returns statistics of heap since the VM was created or cleared
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
     }
 
-    /// args: type_of_elements, address
-    /// 
-    /// generates address of length and pushes it to output
-    fn gen(&mut self) {
-        self.ip += 1;
-        let element_type = self.bytecode[self.ip];
-        let length = self.get_address();
-
-        match element_type {
-            0 => { // u8
-                let ptr = Ptr::allocate(length * mem::size_of::<u8>());
-                let data: Vec<u8> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
-            }
+    /// returns number of calls that have not returned yet
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
 
-            1 => { // u16
-                let ptr = Ptr::allocate(length * mem::size_of::<u16>());
-                let data: Vec<u16> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
+    fn run(&mut self, mut fuel: Option<u64>, until: Option<usize>) -> Result<ExitState, VmError> {
+        // execute has its own loop without checks of fuel and target
+        if fuel.is_none() && until.is_none() {
+            while self.pc < self.program.instructions.len() {
+                if let Some(state) = self.step_instruction()? {
+                    return Ok(state);
+                }
             }
 
-            2 => { // u32
-                let ptr = Ptr::allocate(length * mem::size_of::<u32>());
-                let data: Vec<u32> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
-            }
+            return Ok(self.exit_state(ExitStatus::Finished));
+        }
 
-            3 => { // u64
-                let ptr = Ptr::allocate(length * mem::size_of::<u64>());
-                let data: Vec<u64> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
+        while self.pc < self.program.instructions.len() {
+            match fuel.as_mut() {
+                Some(0) => return Ok(self.exit_state(ExitStatus::OutOfFuel)),
+                Some(fuel) => *fuel -= 1,
+                None => {}
             }
 
-            4 => { // i8
-                let ptr = Ptr::allocate(length * mem::size_of::<i8>());
-                let data: Vec<i8> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
+            if let Some(state) = self.step_instruction()? {
+                return Ok(state);
             }
 
-            5 => { // i16
-                let ptr = Ptr::allocate(length * mem::size_of::<i16>());
-                let data: Vec<i16> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
+            if until.is_some() && until == Some(self.ip()) {
+                return Ok(self.exit_state(ExitStatus::Paused));
             }
+        }
 
-            6 => { // i32
-                let ptr = Ptr::allocate(length * mem::size_of::<i32>());
-                let data: Vec<i32> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
-            }
+        Ok(self.exit_state(ExitStatus::Finished))
+    }
 
-            7 => { // i64
-                let ptr = Ptr::allocate(length * mem::size_of::<i64>());
-                let data: Vec<i64> = vec![0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
+    /// executes like execute but decodes every instruction from bytecode again before it runs it,
+    /// the way instructions were dispatched before bytecode was pre-decoded, it is the baseline of
+    /// the dispatch benchmark
+    #[doc(hidden)]
+    pub fn execute_decoding(&mut self) -> Result<ExitState, VmError> {
+        while self.pc < self.program.instructions.len() {
+            let resolve = |value| match value {
+                Value::Constant(index) => self.constant_values.get(index as usize)
+                    .map(|constant| Value::Immediate(*constant))
+                    .ok_or(Fault::InvalidConstant(index)),
+                value => Ok(value),
+            };
+            let instruction = decode_at(&self.bytecode, self.program.offsets[self.pc])
+                .and_then(|(instruction, _)| instruction.map_values(resolve))
+                .unwrap_or_else(Instruction::Fault);
+
+            if let Some(state) = self.step_decoded(instruction)? {
+                return Ok(state);
             }
+        }
 
-            8 => { // f32
-                let ptr = Ptr::allocate(length * mem::size_of::<f32>());
-                let data: Vec<f32> = vec![0.0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
-            }
+        Ok(self.exit_state(ExitStatus::Finished))
+    }
 
-            9 => { // f64
-                let ptr = Ptr::allocate(length * mem::size_of::<f64>());
-                let data: Vec<f64> = vec![0.0;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
-            }
+    /// executes instruction at pc and moves to the next one, returns state if it halted
+    #[inline(always)]
+    fn step_instruction(&mut self) -> Result<Option<ExitState>, VmError> {
+        self.step_decoded(self.program.instructions[self.pc])
+    }
 
-            10 => { // bool
-                let ptr = Ptr::allocate(length * mem::size_of::<bool>());
-                let data: Vec<bool> = vec![false;length];
-                ptr.set_data(data);
-                self.output.push(Immediate::ADDRESS(self.heap.add(ptr)));
+    /// executes instruction that was decoded at pc and moves to the next one
+    #[inline(always)]
+    fn step_decoded(&mut self, instruction: Instruction) -> Result<Option<ExitState>, VmError> {
+        let pc = self.pc;
+        self.op_pc = pc;
+        self.pc = pc + 1;
+
+        if let Err(error) = self.execute_instruction(instruction) {
+            if self.reference_counting {
+                self.release_pending();
             }
+            self.pc = pc;
+            return Err(error);
+        }
 
-            _ => {}
+        if self.reference_counting {
+            self.release_pending();
         }
+
+        self.executed += 1;
+        if let Some(value) = self.halt.take() {
+            self.pc = pc;
+            return Ok(Some(self.exit_state(ExitStatus::Halted(value))));
+        }
+
+        Ok(None)
     }
 
-    /// args: type, value
-    /// 
-    /// saves value to heap and pushes its address to output
-    fn save(&mut self) {
-        let ptr = self.get_value_as_ptr();
-        let address = self.heap.add(ptr);
-        self.output.push(Immediate::U64(address as u64));
+    /// sets how deep calls can nest before call fails
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
     }
 
-    /// args: type_of_value, value, type_of_index, index
-    /// 
-    /// saves value to heap and sets its address to output at index
-    fn savei(&mut self) {
-        let ptr = self.get_value_as_ptr();
-        let address = self.heap.add(ptr);
-        let index = self.get_index();
-        self.output.set(index, Immediate::U64(address as u64));
+    /// sets how many values input and output can hold before an instruction that sets a value
+    /// past their end fails
+    pub fn set_max_buffer_len(&mut self, len: usize) {
+        self.max_buffer_len = len;
     }
 
-    /// args: address (u64), type_of_value
-    /// 
-    /// loads value from address at heap and pushes it to output
-    fn load(&mut self) {
-        let address = self.get_address();
-        let ptr = self.heap.get(address);
-        self.ip += 1;
-        match self.bytecode[self.ip] {
-            0 => { // u8
-                let value = Immediate::U8(ptr.get_data::<u8>());
-                self.output.push(value);
-            }
+    /// collects garbage before an allocation once threshold objects were allocated since the last collection,
+    /// None turns automatic collection off which is the default
+    pub fn set_gc_threshold(&mut self, threshold: Option<usize>) {
+        self.gc.threshold = threshold;
+    }
 
-            1 => { // u16
-                let value = Immediate::U16(ptr.get_data::<u16>());
-                self.output.push(value);
-            }
+    /// frees every object on heap that can not be reached from an address in input, output, buffers of
+    /// callers or constants, returns number of freed objects
+    ///
+    /// addresses are followed through saved values and arrays, elements of arrays of u64 are treated
+    /// as addresses because addresses are stored in them as u64
+    pub fn collect(&mut self) -> usize {
+        let roots = self.input.as_slice().iter()
+            .chain(self.output.as_slice())
+            .chain(self.frames.iter().flat_map(|frame| frame.input.as_slice().iter().chain(frame.output.as_slice())))
+            .chain(&self.constant_values)
+            .copied();
+        let freed = gc::collect(&mut self.heap, roots);
+        self.gc.record(freed, self.heap.live().count());
+        freed
+    }
 
-            2 => { // u32
-                let value = Immediate::U32(ptr.get_data::<u32>());
-                self.output.push(value);
-            }
+    /// counts references to objects from buffers and saved values and frees an object through `Heap::delete`
+    /// as soon as its last reference is dropped, has to be enabled before execution starts
+    ///
+    /// references dropped by an instruction are released in order after it finished, an object that is freed
+    /// releases the references it holds after references that were dropped before, addresses stored in arrays
+    /// by astore are counted, elements loaded back by aload are u64 that are not counted, cycles are only
+    /// freed by collect
+    pub fn set_reference_counting(&mut self, enabled: bool) {
+        self.reference_counting = enabled;
+    }
 
-            3 => { // u64
-                let value = Immediate::U64(ptr.get_data::<u64>());
-                self.output.push(value);
-            }
+    /// returns statistics of garbage collection since the VM was created or cleared
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc.stats
+    }
 
-            4 => { // i8
-                let value = Immediate::I8(ptr.get_data::<i8>());
-                self.output.push(value);
-            }
+    /// resets VM to the state it was created in, every object on heap is freed after leaks were reported
+    pub fn clear(&mut self) {
+        self.report_leaks();
+        self.input = Buffer::new();
+        self.output = Buffer::new();
+        self.pc = 0;
+        self.heap = Heap::new();
+        self.constant_values = add_constants(&mut self.heap, &self.constants);
+        self.gc = Gc { threshold: self.gc.threshold, ..Gc::default() };
+        self.released.clear();
+        self.frames = Vec::new();
+        self.overflow = Overflow::Trap;
+        self.halt = None;
+        self.executed = 0;
+    }
 
-            5 => { // i16
-                let value = Immediate::I16(ptr.get_data::<i16>());
-                self.output.push(value);
+    /// passes objects that were never freed to leak report
+    fn report_leaks(&mut self) {
+        if let Some(mut report) = self.leak_report.take() {
+            let leaks: Vec<HeapObjectInfo> = self.leaks().collect();
+            if !leaks.is_empty() {
+                report(&leaks);
             }
+            self.leak_report = Some(report);
+        }
+    }
 
-            6 => { // i32
-                let value = Immediate::I16(ptr.get_data::<i16>());
-                self.output.push(value);
-            }
+    fn exit_state(&self, status: ExitStatus) -> ExitState {
+        ExitState {
+            status,
+            ip: self.ip(),
+            instructions: self.executed,
+        }
+    }
 
-            7 => { // i64
-                let value = Immediate::I64(ptr.get_data::<i64>());
-                self.output.push(value);
-            }
+    /// instructions that allocate or change frames are not inlined to keep this loop small
+    #[inline(always)]
+    fn execute_instruction(&mut self, instruction: Instruction) -> Result<(), VmError> {
+        match instruction {
+            Instruction::Nop => Ok(()),
+            Instruction::Push(value) => self.push(value),
+            Instruction::Pop => self.pop(),
+            Instruction::Popi(index) => self.popi(index),
+            Instruction::Set(value, index) => self.set(value, index),
+            Instruction::Get(index) => self.get(index),
+            Instruction::Geti(o_index, i_index) => self.geti(o_index, i_index),
+            Instruction::ClearI => self.clear_i(),
+            Instruction::ClearO => self.clear_o(),
+            Instruction::Gen(element_type, length) => self.gen(element_type, length),
+            Instruction::Save(value) => self.save(value),
+            Instruction::Savei(value, index) => self.savei(value, index),
+            Instruction::Load(address, value_type) => self.load(address, value_type),
+            Instruction::Loadi(address, index, value_type) => self.loadi(address, index, value_type),
+            Instruction::Less => self.less(),
+            Instruction::Great => self.great(),
+            Instruction::Eq => self.eq(),
+            Instruction::Jmp => self.jmp(),
+            Instruction::Add => self.add(),
+            Instruction::Sub => self.sub(),
+            Instruction::Mul => self.mul(),
+            Instruction::Div => self.div(),
+            Instruction::Rem => self.rem(),
+            Instruction::Neg => self.neg(),
+            Instruction::Abs => self.abs(),
+            Instruction::JmpIf => self.jmp_if(),
+            Instruction::JmpUnless => self.jmp_unless(),
+            Instruction::Call(count) => self.call(count),
+            Instruction::Ret(count) => self.ret(count),
+            Instruction::Halt => self.halt(),
+            Instruction::Ovf(mode) => self.ovf(mode),
+            Instruction::Cast(value_type) => self.cast(value_type),
+            Instruction::Castc(value_type) => self.castc(value_type),
+            Instruction::Store => self.store(),
+            Instruction::Free => self.free(),
+            Instruction::Aload => self.aload(),
+            Instruction::Astore => self.astore(),
+            Instruction::Alen => self.alen(),
+            Instruction::Fault(fault) => Err(self.fault(fault)),
+        }
+    }
 
-            8 => { // f32
-                let value = Immediate::F32(ptr.get_data::<f32>());
-                self.output.push(value);
-            }
+    /// args: type, value
+    ///
+    /// pushes value to input
+    fn push(&mut self, value: Value) -> Result<(), VmError> {
+        let value = self.get_immediate(value);
+        self.retain(value);
+        self.input.push(value);
+        Ok(())
+    }
 
-            9 => { // f64
-                let value = Immediate::F64(ptr.get_data::<f64>());
-                self.output.push(value);
-            }
+    /// pops value from output and pushes it to input
+    fn pop(&mut self) -> Result<(), VmError> {
+        let value = self.pop_output()?;
+        self.input.push(value);
+        Ok(())
+    }
 
-            10 => { // bool
-                let value = Immediate::BOOL(ptr.get_data::<bool>());
-                self.output.push(value);
-            }
+    /// args: type, index
+    ///
+    /// pops value from output and sets it to input at index
+    fn popi(&mut self, index: Value) -> Result<(), VmError> {
+        let value = self.pop_output()?;
+        let index = self.get_index(index)?;
+        let replaced = self.set_input(index, value)?;
+        self.release(replaced);
+        Ok(())
+    }
 
-            _ => {}
-        }
+    /// args: type, value, type, index
+    ///
+    /// sets value of input at index
+    fn set(&mut self, value: Value, index: Value) -> Result<(), VmError> {
+        let value = self.get_immediate(value);
+        let index = self.get_index(index)?;
+        self.retain(value);
+        let replaced = self.set_input(index, value)?;
+        self.release(replaced);
+        Ok(())
     }
 
-    /// args: address (u64), type_of_index, index, type_of_value,
-    /// 
-    /// loads value from address at heap and sets it to output at index
-    fn loadi(&mut self) {
-        let address = self.get_address();
-        let ptr = self.heap.get(address);
-        let index = self.get_index();
-        self.ip += 1;
-        match self.bytecode[self.ip] {
-            0 => { // u8
-                let value = Immediate::U8(ptr.get_data::<u8>());
-                self.output.set(index, value);
-            }
+    /// args: type, index
+    ///
+    /// pushes value of output to index
+    fn get(&mut self, index: Value) -> Result<(), VmError> {
+        let index = self.get_index(index)?;
+        let value = self.output.get(index);
+        self.retain(value);
+        self.input.push(value);
+        Ok(())
+    }
 
-            1 => { // u16
-                let value = Immediate::U16(ptr.get_data::<u16>());
-                self.output.set(index, value);
-            }
+    /// args: type, output_index, type, input_index
+    ///
+    /// gets value of output at index and sets it to input at index
+    fn geti(&mut self, o_index: Value, i_index: Value) -> Result<(), VmError> {
+        let o_index = self.get_index(o_index)?;
+        let i_index = self.get_index(i_index)?;
+        let value = self.output.get(o_index);
+        self.retain(value);
+        let replaced = self.set_input(i_index, value)?;
+        self.release(replaced);
+        Ok(())
+    }
 
-            2 => { // u32
-                let value = Immediate::U32(ptr.get_data::<u32>());
-                self.output.set(index, value);
-            }
+    /// clears input
+    fn clear_i(&mut self) -> Result<(), VmError> {
+        if self.reference_counting {
+            self.released.extend(addresses(self.input.as_slice()));
+        }
+        self.input.clear();
+        Ok(())
+    }
 
-            3 => { // u64
-                let value = Immediate::U64(ptr.get_data::<u64>());
-                self.output.set(index, value);
-            }
+    /// clears output
+    fn clear_o(&mut self) -> Result<(), VmError> {
+        if self.reference_counting {
+            self.released.extend(addresses(self.output.as_slice()));
+        }
+        self.output.clear();
+        Ok(())
+    }
 
-            4 => { // i8
-                let value = Immediate::I8(ptr.get_data::<i8>());
-                self.output.set(index, value);
-            }
+    /// args: type_of_elements, address
+    ///
+    /// generates array of length and pushes its address to output
+    #[inline(never)]
+    fn gen(&mut self, element_type: u8, length: u64) -> Result<(), VmError> {
+        let element_size = type_size(element_type)
+            .ok_or_else(|| VmError::InvalidTypeTag { ip: self.op_ip(), opcode: self.opcode(), tag: element_type })?;
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        self.reserve(length.saturating_mul(element_size))?;
+
+        let array = Array::new(element_type, length).expect("type tag was checked");
+        let address = self.allocate(HeapObject::Array(array));
+        self.output.push(Immediate::ADDRESS(address));
+        Ok(())
+    }
 
-            5 => { // i16
-                let value = Immediate::I16(ptr.get_data::<i16>());
-                self.output.set(index, value);
-            }
+    /// args: type, value
+    ///
+    /// saves value to heap and pushes its address to output
+    #[inline(never)]
+    fn save(&mut self, value: Value) -> Result<(), VmError> {
+        let value = self.get_scalar(value)?;
+        let object = HeapObject::Scalar(value);
+        self.reserve(object.size())?;
+        let address = self.allocate(object);
+        self.retain(value);
+        self.output.push(Immediate::ADDRESS(address));
+        Ok(())
+    }
 
-            6 => { // i32
-                let value = Immediate::I16(ptr.get_data::<i16>());
-                self.output.set(index, value);
-            }
+    /// args: type_of_value, value, type_of_index, index
+    ///
+    /// saves value to heap and sets its address to output at index
+    #[inline(never)]
+    fn savei(&mut self, value: Value, index: Value) -> Result<(), VmError> {
+        let value = self.get_scalar(value)?;
+        let index = self.get_index(index)?;
+        if index as u64 >= self.output.len() && index >= self.max_buffer_len {
+            let limit = self.max_buffer_len;
+            return Err(VmError::BufferLimitExceeded { ip: self.op_ip(), opcode: self.opcode(), index, limit });
+        }
 
-            7 => { // i64
-                let value = Immediate::I64(ptr.get_data::<i64>());
-                self.output.set(index, value);
-            }
+        let object = HeapObject::Scalar(value);
+        self.reserve(object.size())?;
+        let address = self.allocate(object);
+        self.retain(value);
+        let replaced = self.set_output(index, Immediate::ADDRESS(address))?;
+        self.release(replaced);
+        Ok(())
+    }
 
-            8 => { // f32
-                let value = Immediate::F32(ptr.get_data::<f32>());
-                self.output.set(index, value);
-            }
+    /// args: address (u64), type_of_value
+    ///
+    /// loads value from address at heap and pushes it to output
+    #[inline(never)]
+    fn load(&mut self, address: u64, value_type: u8) -> Result<(), VmError> {
+        let value = self.get_value(Address::from_u64(address), value_type)?;
+        self.retain(value);
+        self.output.push(value);
+        Ok(())
+    }
 
-            9 => { // f64
-                let value = Immediate::F64(ptr.get_data::<f64>());
-                self.output.set(index, value);
-            }
+    /// args: address (u64), type_of_index, index, type_of_value,
+    ///
+    /// loads value from address at heap and sets it to output at index
+    #[inline(never)]
+    fn loadi(&mut self, address: u64, index: Value, value_type: u8) -> Result<(), VmError> {
+        let value = self.get_value(Address::from_u64(address), value_type)?;
+        let index = self.get_index(index)?;
+        self.retain(value);
+        let replaced = self.set_output(index, value)?;
+        self.release(replaced);
+        Ok(())
+    }
 
-            10 => { // bool
-                let value = Immediate::BOOL(ptr.get_data::<bool>());
-                self.output.set(index, value);
-            }
+    /// pops address and value from input and writes value to address at heap,
+    /// value has to have the type it was saved with
+    #[inline(never)]
+    fn store(&mut self) -> Result<(), VmError> {
+        let address = self.pop_address()?;
+        let value = self.pop_input()?;
+        let replaced = match self.get_object_mut(address)? {
+            HeapObject::Scalar(saved) if value_tag(*saved) == value_tag(value) => mem::replace(saved, value),
+            _ => return Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+        };
 
-            _ => {}
+        self.retain(value);
+        self.release(replaced);
+        Ok(())
+    }
+
+    /// pops address from input and frees value or array at it, blobs of constants can not be freed
+    #[inline(never)]
+    fn free(&mut self) -> Result<(), VmError> {
+        let address = self.pop_address()?;
+        if self.constant_values.contains(&Immediate::ADDRESS(address)) {
+            return Err(VmError::InvalidHeapAddress { ip: self.op_ip(), opcode: self.opcode(), address });
         }
+
+        let object = self.heap.delete(address).map_err(|error| match error {
+            HeapError::Invalid => VmError::InvalidHeapAddress { ip: self.op_ip(), opcode: self.opcode(), address },
+            HeapError::Freed => VmError::DoubleFree { ip: self.op_ip(), opcode: self.opcode(), address },
+        })?;
+
+        self.release_held(&object);
+        Ok(())
+    }
+
+    /// pops address and index from input and pushes element of array at index to output
+    #[inline(never)]
+    fn aload(&mut self) -> Result<(), VmError> {
+        let (address, index) = self.pop_element()?;
+        let value = self.get_object(address)?.element(index);
+        self.output.push(value);
+        Ok(())
+    }
+
+    /// pops address, index and value from input and writes value to array at index,
+    /// value has to have the type of elements of array
+    #[inline(never)]
+    fn astore(&mut self) -> Result<(), VmError> {
+        let (address, index) = self.pop_element()?;
+        let value = self.pop_input()?;
+        let replaced = self.get_object_mut(address)?.set_element(index, value)
+            .ok_or_else(|| VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() })?;
+
+        self.retain(value);
+        self.release(replaced);
+        Ok(())
+    }
+
+    /// pops address from input and pushes length of array as u64 to output
+    fn alen(&mut self) -> Result<(), VmError> {
+        let address = self.pop_address()?;
+        let (_, length) = self.get_elements(address)?;
+        self.output.push(Immediate::U64(length as u64));
+        Ok(())
     }
 
     /// pops values from input and compares them, pushes result to output
-    fn less(&mut self) {
-        let v1 = self.input.pop();
-        let v2 = self.input.pop();
+    fn less(&mut self) -> Result<(), VmError> {
+        let v1 = self.pop_input()?;
+        let v2 = self.pop_input()?;
         let v = v1 < v2;
         self.output.push(Immediate::BOOL(v));
+        Ok(())
     }
 
     /// pops values from input and compares them, pushes result to output
-    fn great(&mut self) {
-        let v1 = self.input.pop();
-        let v2 = self.input.pop();
+    fn great(&mut self) -> Result<(), VmError> {
+        let v1 = self.pop_input()?;
+        let v2 = self.pop_input()?;
         let v = v1 > v2;
         self.output.push(Immediate::BOOL(v));
+        Ok(())
     }
 
     /// pops values from input and compares them, pushes result to output
-    fn eq(&mut self) {
-        let v1 = self.input.pop();
-        let v2 = self.input.pop();
+    fn eq(&mut self) -> Result<(), VmError> {
+        let v1 = self.pop_input()?;
+        let v2 = self.pop_input()?;
         let v = v1 == v2;
         self.output.push(Immediate::BOOL(v));
+        Ok(())
     }
- 
-    /// pops number from input and jumps to its value  
-    fn jmp(&mut self) {
-        let v = self.input.pop();
-        let mut index = 0;
-        match v {
-            Immediate::U8(v) => { index = v as usize; }
-            Immediate::U16(v) => { index = v as usize; }
-            Immediate::U32(v) => { index = v as usize; }
-            Immediate::U64(v) => { index = v as usize; }
-            Immediate::I8(v) => { index = v as usize; }
-            Immediate::I16(v) => { index = v as usize; }
-            Immediate::I32(v) => { index = v as usize; }
-            Immediate::I64(v) => { index = v as usize; }
-            Immediate::F32(v) => { index = v as usize; }
-            Immediate::F64(v) => { index = v as usize; }
-            Immediate::BOOL(v) => { index = v as usize; }
-            Immediate::ADDRESS(v) => { index = v as usize; }
 
-            _ => { index = self.ip + 1; }
+    /// pops number from input and jumps to its value
+    fn jmp(&mut self) -> Result<(), VmError> {
+        let index = self.pop_target()?;
+        self.jump(index)
+    }
+
+    /// pops number and bool from input, jumps to the number if bool is true
+    fn jmp_if(&mut self) -> Result<(), VmError> {
+        let index = self.pop_target()?;
+        if self.pop_condition()? {
+            self.jump(index)?;
         }
+        Ok(())
+    }
 
-        self.ip = index;
-        self.jmp = true;
+    /// pops number and bool from input, jumps to the number if bool is false
+    fn jmp_unless(&mut self) -> Result<(), VmError> {
+        let index = self.pop_target()?;
+        if !self.pop_condition()? {
+            self.jump(index)?;
+        }
+        Ok(())
+    }
+
+    /// args: type, count
+    ///
+    /// pops number from input and calls function at its value,
+    /// moves count values from input to input of the function, function starts with empty output,
+    /// buffers are not changed if call fails
+    #[inline(never)]
+    fn call(&mut self, count: Value) -> Result<(), VmError> {
+        let count = self.get_index(count)?;
+        if self.frames.len() >= self.max_call_depth {
+            return Err(VmError::CallDepthExceeded { ip: self.op_ip(), opcode: self.opcode(), depth: self.max_call_depth });
+        }
+
+        let values = self.input.as_slice();
+        let target = match values.last() {
+            Some(&target) if values.len() > count => target,
+            _ => return Err(VmError::StackUnderflow { ip: self.op_ip(), opcode: self.opcode() }),
+        };
+        let pc = self.instruction_at(self.target(target)?)?;
+
+        self.pop_input()?;
+        let args = self.input.split_off(count).expect("input holds count arguments");
+        self.frames.push(Frame {
+            return_pc: self.pc,
+            input: mem::replace(&mut self.input, args),
+            output: mem::replace(&mut self.output, Buffer::new()),
+        });
+
+        self.pc = pc;
+        Ok(())
+    }
+
+    /// args: type, count
+    ///
+    /// returns from function, moves count values from output of the function to output
+    #[inline(never)]
+    fn ret(&mut self, count: Value) -> Result<(), VmError> {
+        let count = self.get_index(count)?;
+        if self.frames.is_empty() {
+            return Err(VmError::ReturnWithoutCall { ip: self.op_ip(), opcode: self.opcode() });
+        }
+
+        let results = self.output.split_off(count)
+            .ok_or_else(|| VmError::StackUnderflow { ip: self.op_ip(), opcode: self.opcode() })?;
+        let frame = self.frames.pop().expect("frame was checked");
+
+        if self.reference_counting {
+            self.released.extend(addresses(self.input.as_slice()));
+            self.released.extend(addresses(self.output.as_slice()));
+        }
+
+        self.input = frame.input;
+        self.output = frame.output;
+        self.output.append(results);
+
+        self.pc = frame.return_pc;
+        Ok(())
+    }
+
+    /// stops execution, pops exit value from input if input is not empty, in reference counting mode
+    /// the exit value is released like any popped value, so an address in it does not keep its object
+    fn halt(&mut self) -> Result<(), VmError> {
+        let value = self.input.pop();
+        if let Some(value) = value {
+            self.release(value);
+        }
+        self.halt = Some(value);
+        Ok(())
+    }
+
+    /// args: mode
+    ///
+    /// sets overflow of arithmetic instructions, 0 is trap, 1 is wrap and 2 is saturate,
+    /// mode has to be an integer
+    fn ovf(&mut self, mode: Value) -> Result<(), VmError> {
+        self.overflow = match integer(self.get_immediate(mode)) {
+            Some(0) => Overflow::Trap,
+            Some(1) => Overflow::Wrap,
+            Some(2) => Overflow::Saturate,
+            _ => return Err(VmError::InvalidOperand { ip: self.op_ip(), opcode: self.opcode() }),
+        };
+        Ok(())
     }
 
     /// pops numbers from input, adds two numbers and pushes result to output
-    fn add(&mut self) {
-        let num1 = self.input.pop();
-        let num2 = self.input.pop();
-        let num = num1 + num2;
-        self.output.push(num);
+    fn add(&mut self) -> Result<(), VmError> {
+        let num1 = self.pop_input()?;
+        let num2 = self.pop_input()?;
+        let num = num1.add(num2, self.overflow);
+        self.push_result(num)
     }
 
     /// pops numbers from input, substracts two numbers and pushes result to output
-    fn sub(&mut self) {
-        let num1 = self.input.pop();
-        let num2 = self.input.pop();
-        let num = num1 - num2;
-        self.output.push(num);
+    fn sub(&mut self) -> Result<(), VmError> {
+        let num1 = self.pop_input()?;
+        let num2 = self.pop_input()?;
+        let num = num1.sub(num2, self.overflow);
+        self.push_result(num)
     }
 
     /// pops numbers from input, multiplies two numbers and pushes result to output
-    fn mul(&mut self) {
-        let num1 = self.input.pop();
-        let num2 = self.input.pop();
-        let num = num1 * num2;
-        self.output.push(num);
+    fn mul(&mut self) -> Result<(), VmError> {
+        let num1 = self.pop_input()?;
+        let num2 = self.pop_input()?;
+        let num = num1.mul(num2, self.overflow);
+        self.push_result(num)
     }
 
     /// pops numbers from input, divides two numbers and pushes result to output
-    fn div(&mut self) {
-        let num1 = self.input.pop();
-        let num2 = self.input.pop();
-        let num = num1 / num2;
-        self.output.push(num);
+    fn div(&mut self) -> Result<(), VmError> {
+        let num1 = self.pop_input()?;
+        let num2 = self.pop_input()?;
+        let num = num1.div(num2, self.overflow);
+        self.push_result(num)
     }
 
-    fn get_immediate(&mut self) -> Immediate {
-        self.ip += 1;
+    /// pops numbers from input, divides two numbers and pushes remainder to output
+    fn rem(&mut self) -> Result<(), VmError> {
+        let num1 = self.pop_input()?;
+        let num2 = self.pop_input()?;
+        let num = num1.rem(num2, self.overflow);
+        self.push_result(num)
+    }
 
-        match self.bytecode[self.ip] {
-            0 => { // u8
-                self.ip += 1;
-                let value = self.bytecode[self.ip];
-                Immediate::U8(value)
-            }
+    /// pops number from input, negates it and pushes result to output
+    fn neg(&mut self) -> Result<(), VmError> {
+        let num = self.pop_input()?;
+        self.push_result(num.neg(self.overflow))
+    }
 
-            1 => { // u16
-                self.ip += 1;
-                let size = mem::size_of::<u16>();
-                let value = u16::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::U16(value)
-            }
+    /// pops number from input and pushes its absolute value to output
+    fn abs(&mut self) -> Result<(), VmError> {
+        let num = self.pop_input()?;
+        self.push_result(num.abs(self.overflow))
+    }
 
-            2 => { // u32
-                self.ip += 1;
-                let size = mem::size_of::<u32>();
-                let value = u32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::U32(value)
-            }
+    /// args: type_of_result
+    ///
+    /// pops value from input, converts it to type and pushes it to output
+    fn cast(&mut self, value_type: u8) -> Result<(), VmError> {
+        self.cast_value(value_type, false)
+    }
 
-            3 => { // u64
-                self.ip += 1;
-                let size = mem::size_of::<u64>();
-                let value = u64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::U64(value)
-            }
+    /// args: type_of_result
+    ///
+    /// pops value from input, converts it to type and pushes it to output,
+    /// fails if value does not fit into type
+    fn castc(&mut self, value_type: u8) -> Result<(), VmError> {
+        self.cast_value(value_type, true)
+    }
 
-            4 => { // i8
-                self.ip += 1;
-                let value = self.bytecode[self.ip] as i8;
-                Immediate::I8(value)
-            }
+    #[inline(never)]
+    fn cast_value(&mut self, tag: u8, checked: bool) -> Result<(), VmError> {
+        if tag > 10 {
+            return Err(VmError::InvalidTypeTag { ip: self.op_ip(), opcode: self.opcode(), tag });
+        }
 
-            5 => { // i16
-                self.ip += 1;
-                let size = mem::size_of::<i16>();
-                let value = i16::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::I16(value)
-            }
+        let value = self.pop_input()?;
+        let value = value.cast(tag, checked).map_err(|error| match error {
+            ArithmeticError::Overflow => VmError::CastOutOfRange { ip: self.op_ip(), opcode: self.opcode() },
+            _ => VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() },
+        })?;
 
-            6 => { // i32
-                self.ip += 1;
-                let size = mem::size_of::<i32>();
-                let value = i32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::I32(value)
-            }
+        self.output.push(value);
+        Ok(())
+    }
 
-            7 => { // i64
-                self.ip += 1;
-                let size = mem::size_of::<i64>();
-                let value = i64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::I64(value)
+    /// pushes result of arithmetic to output
+    fn push_result(&mut self, value: Result<Immediate, ArithmeticError>) -> Result<(), VmError> {
+        let value = value.map_err(|error| {
+            let (ip, opcode) = (self.op_ip(), self.opcode());
+            match error {
+                ArithmeticError::TypeMismatch => VmError::TypeMismatch { ip, opcode },
+                ArithmeticError::Overflow => VmError::ArithmeticOverflow { ip, opcode },
+                ArithmeticError::DivisionByZero => VmError::DivisionByZero { ip, opcode },
             }
+        })?;
 
-            8 => { // f32
-                self.ip += 1;
-                let size = mem::size_of::<f32>();
-                let value = f32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::F32(value)
-            }
+        self.output.push(value);
+        Ok(())
+    }
 
-            9 => { // f64
-                self.ip += 1;
-                let size = mem::size_of::<f64>();
-                let value = f64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                Immediate::F64(value)
-            }
+    /// pops value from input, fails if input is empty, the value is consumed by the instruction
+    /// and its reference is released unless the instruction retains it
+    fn pop_input(&mut self) -> Result<Immediate, VmError> {
+        let value = self.input.pop().ok_or_else(|| VmError::StackUnderflow { ip: self.op_ip(), opcode: self.opcode() })?;
+        self.release(value);
+        Ok(value)
+    }
 
-            10 => { // bool
-                self.ip += 1;
-                let value = self.bytecode[self.ip] != 0;
-                Immediate::BOOL(value)
-            }
+    /// pops value from output, fails if output is empty
+    fn pop_output(&mut self) -> Result<Immediate, VmError> {
+        self.output.pop().ok_or_else(|| VmError::StackUnderflow { ip: self.op_ip(), opcode: self.opcode() })
+    }
 
-            11 => { // maximum index of input
-                let index = self.input.len();
-                if index > 0 {
-                    return Immediate::U64(index - 1);
-                }
+    /// pops jump target from input, it has to be an integer that is not negative
+    fn pop_target(&mut self) -> Result<usize, VmError> {
+        let value = self.pop_input()?;
+        self.target(value)
+    }
 
-                Immediate::U64(index)
-            }
+    /// returns jump target of value, it has to be an integer that is not negative
+    fn target(&self, value: Immediate) -> Result<usize, VmError> {
+        match integer(value) {
+            Some(target) if target >= 0 => Ok(usize::try_from(target).unwrap_or(usize::MAX)),
+            _ => Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+        }
+    }
 
-            12 => { // maximum index of output
-                let index = self.output.len();
-                if index > 0 {
-                    return Immediate::U64(index - 1);
-                }
+    /// pops condition of a jump from input, it has to be a bool
+    fn pop_condition(&mut self) -> Result<bool, VmError> {
+        match self.pop_input()? {
+            Immediate::BOOL(v) => Ok(v),
+            _ => Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+        }
+    }
 
-                Immediate::U64(index)
-            }
+    /// counts another reference to address in reference counting mode
+    #[inline(always)]
+    fn retain(&mut self, value: Immediate) {
+        if self.reference_counting {
+            self.retain_address(value);
+        }
+    }
 
-            _ => { Immediate::NONE()}
+    /// drops reference to address in reference counting mode, it is released after the instruction
+    #[inline(always)]
+    fn release(&mut self, value: Immediate) {
+        if self.reference_counting {
+            self.release_address(value);
         }
     }
 
-    fn get_index(&mut self) -> usize {
-        self.ip += 1;
+    #[cold]
+    #[inline(never)]
+    fn retain_address(&mut self, value: Immediate) {
+        if let Immediate::ADDRESS(address) = value {
+            self.heap.retain(address);
+        }
+    }
 
-        match self.bytecode[self.ip] {
-            0 => { // u8
-                self.ip += 1;
-                let value = self.bytecode[self.ip];
-                value as usize
-            }
+    #[cold]
+    #[inline(never)]
+    fn release_address(&mut self, value: Immediate) {
+        if let Immediate::ADDRESS(address) = value {
+            self.released.push(address);
+        }
+    }
 
-            1 => { // u16
-                self.ip += 1;
-                let size = mem::size_of::<u16>();
-                let value = u16::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
+    /// drops references held by object that was deleted from heap
+    fn release_held(&mut self, object: &HeapObject) {
+        match object {
+            HeapObject::Scalar(value) => self.release(*value),
+            HeapObject::Array(array) => {
+                for (_, address) in array.addresses() {
+                    self.release(Immediate::ADDRESS(address));
+                }
             }
+            HeapObject::Bytes(_) => {}
+        }
+    }
 
-            2 => { // u32
-                self.ip += 1;
-                let size = mem::size_of::<u32>();
-                let value = u32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
+    /// releases dropped references in order and deletes objects whose last reference was dropped,
+    /// references held by deleted objects are released after the ones dropped before them
+    #[inline(never)]
+    fn release_pending(&mut self) {
+        let mut next = 0;
+        while next < self.released.len() {
+            let address = self.released[next];
+            next += 1;
+            if self.heap.release(address) {
+                if let Ok(object) = self.heap.delete(address) {
+                    self.release_held(&object);
+                }
             }
+        }
 
-            3 => { // u64
-                self.ip += 1;
-                let size = mem::size_of::<u64>();
-                let value = u64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
-            }
+        self.released.clear();
+    }
 
-            4 => { // i8
-                self.ip += 1;
-                let value = self.bytecode[self.ip] as i8;
-                value as usize
-            }
+    /// collects garbage when threshold of allocations is reached and checks that an object of size
+    /// fits into limits of heap, has to be called before every allocation
+    fn reserve(&mut self, size: usize) -> Result<(), VmError> {
+        if self.gc.due() {
+            self.collect();
+        }
 
-            5 => { // i16
-                self.ip += 1;
-                let size = mem::size_of::<i16>();
-                let value = i16::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
-            }
+        let limit = if size > self.limits.max_allocation {
+            HeapLimit::Allocation
+        } else if self.heap.objects() >= self.limits.max_objects {
+            HeapLimit::Objects
+        } else if size > self.limits.max_bytes.saturating_sub(self.heap.bytes()) {
+            HeapLimit::Bytes
+        } else {
+            return Ok(());
+        };
 
-            6 => { // i32
-                self.ip += 1;
-                let size = mem::size_of::<i32>();
-                let value = i32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
-            }
+        Err(VmError::HeapLimitExceeded { ip: self.op_ip(), opcode: self.opcode(), limit, size })
+    }
 
-            7 => { // i64
-                self.ip += 1;
-                let size = mem::size_of::<i64>();
-                let value = i64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
-            }
+    /// adds object whose size was reserved to heap
+    fn allocate(&mut self, object: HeapObject) -> Address {
+        self.gc.allocations += 1;
+        self.heap.add(object, Some(self.op_ip()))
+    }
 
-            8 => { // f32
-                self.ip += 1;
-                let size = mem::size_of::<f32>();
-                let value = f32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
-            }
+    /// returns object at address of heap, fails if there is none
+    fn get_object(&self, address: Address) -> Result<&HeapObject, VmError> {
+        self.heap.get(address).map_err(|error| heap_error(error, self.op_ip(), self.opcode(), address))
+    }
 
-            9 => { // f64
-                self.ip += 1;
-                let size = mem::size_of::<f64>();
-                let value = f64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                self.ip += size - 1;
-                value as usize
-            }
+    /// returns object at address of heap to change it, fails if there is none
+    fn get_object_mut(&mut self, address: Address) -> Result<&mut HeapObject, VmError> {
+        let (ip, opcode) = (self.op_ip(), self.opcode());
+        self.heap.get_mut(address).map_err(|error| heap_error(error, ip, opcode, address))
+    }
 
-            10 => { // bool
-                self.ip += 1;
-                let value = self.bytecode[self.ip] != 0;
-                value as usize
-            }
+    /// pops heap address from input, it has to be an address or a u64 loaded from an array
+    fn pop_address(&mut self) -> Result<Address, VmError> {
+        match self.pop_input()? {
+            Immediate::ADDRESS(v) => Ok(v),
+            Immediate::U64(v) => Ok(Address::from_u64(v)),
+            _ => Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+        }
+    }
 
-            11 => { // maximum index of input
-                let index = self.input.len();
-                if index > 0 {
-                    return (index - 1) as usize;
-                }
+    /// returns type of elements and length of array at address of heap, fails if address holds a value
+    fn get_elements(&self, address: Address) -> Result<(u8, usize), VmError> {
+        self.get_object(address)?.elements().ok_or_else(|| VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() })
+    }
 
-                index as usize
-            }
+    /// pops address and index of an element from input,
+    /// fails if index is not an integer or out of bounds
+    fn pop_element(&mut self) -> Result<(Address, usize), VmError> {
+        let address = self.pop_address()?;
+        let index = match self.pop_input()? {
+            Immediate::U8(v) => v as i128,
+            Immediate::U16(v) => v as i128,
+            Immediate::U32(v) => v as i128,
+            Immediate::U64(v) => v as i128,
+            Immediate::I8(v) => v as i128,
+            Immediate::I16(v) => v as i128,
+            Immediate::I32(v) => v as i128,
+            Immediate::I64(v) => v as i128,
+            Immediate::ADDRESS(v) => v.to_u64() as i128,
+            _ => return Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+        };
 
-            12 => { // maximum index of output
-                let index = self.output.len();
-                if index > 0 {
-                    return (index - 1) as usize;
-                }
+        let (_, length) = self.get_elements(address)?;
+        if index < 0 || index >= length as i128 {
+            return Err(VmError::IndexOutOfBounds { ip: self.op_ip(), opcode: self.opcode(), index, length });
+        }
 
-                index as usize
-            }
+        Ok((address, index as usize))
+    }
 
-            _ => { 0 as usize}
+    /// returns value of type at address of heap, fails if it was saved with another type
+    fn get_value(&self, address: Address, value_type: u8) -> Result<Immediate, VmError> {
+        match *self.get_object(address)? {
+            HeapObject::Scalar(value) if value_tag(value) == Some(value_type) => Ok(value),
+            _ if value_type > 10 => Err(VmError::InvalidTypeTag { ip: self.op_ip(), opcode: self.opcode(), tag: value_type }),
+            _ => Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
         }
     }
 
-    fn get_value_as_ptr(&mut self) -> Ptr {
-        self.ip += 1;
-
-        match self.bytecode[self.ip] {
-            0 => { // u8
-                self.ip += 1;
-                let value = self.bytecode[self.ip];
-                let ptr = Ptr::allocate(mem::size_of::<u8>());
-                ptr.set_data(value);
-                ptr
-            }
-
-            1 => { // u16
-                self.ip += 1;
-                let size = mem::size_of::<u16>();
-                let value = u16::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
-
-            2 => { // u32
-                self.ip += 1;
-                let size = mem::size_of::<u32>();
-                let value = u32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    /// jumps to instruction at byte offset, offset of the end of bytecode finishes execution,
+    /// offsets inside of an instruction or past the end fail
+    fn jump(&mut self, target: usize) -> Result<(), VmError> {
+        self.pc = self.instruction_at(target)?;
+        Ok(())
+    }
 
-            3 => { // u64
-                self.ip += 1;
-                let size = mem::size_of::<u64>();
-                let value = u64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    /// returns index of instruction at jump target, fails like jump
+    fn instruction_at(&self, target: usize) -> Result<usize, VmError> {
+        self.program.index_of(target)
+            .ok_or_else(|| VmError::InvalidJumpTarget { ip: self.op_ip(), opcode: self.opcode(), target })
+    }
 
-            4 => { // i8
-                self.ip += 1;
-                let value = self.bytecode[self.ip] as i8;
-                let ptr = Ptr::allocate(mem::size_of::<i8>());
-                ptr.set_data(value);
-                ptr
-            }
+    /// returns ip of the instruction that is executing
+    fn op_ip(&self) -> usize {
+        self.program.offsets[self.op_pc]
+    }
 
-            5 => { // i16
-                self.ip += 1;
-                let size = mem::size_of::<i16>();
-                let value = i16::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    /// returns opcode of the instruction that is executing
+    fn opcode(&self) -> u8 {
+        self.bytecode[self.op_ip()]
+    }
 
-            6 => { // i32
-                self.ip += 1;
-                let size = mem::size_of::<i32>();
-                let value = i32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    /// returns error of bytecode that could not be decoded
+    fn fault(&self, fault: Fault) -> VmError {
+        let (ip, opcode) = (self.op_ip(), self.opcode());
+        match fault {
+            Fault::UnknownOpcode => VmError::UnknownOpcode { ip, opcode },
+            Fault::TruncatedOperand => VmError::TruncatedOperand { ip, opcode },
+            Fault::InvalidTypeTag(tag) => VmError::InvalidTypeTag { ip, opcode, tag },
+            Fault::InvalidConstant(index) => VmError::InvalidConstant { ip, opcode, index: index as usize },
+        }
+    }
 
-            7 => { // i64
-                self.ip += 1;
-                let size = mem::size_of::<i64>();
-                let value = i64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    fn get_immediate(&self, value: Value) -> Immediate {
+        match value {
+            Value::Immediate(value) => value,
+            Value::InputMax => Immediate::U64(self.input.len().saturating_sub(1)),
+            Value::OutputMax => Immediate::U64(self.output.len().saturating_sub(1)),
+            Value::Constant(_) => unreachable!("constants are replaced when bytecode is decoded"),
+        }
+    }
 
-            8 => { // f32
-                self.ip += 1;
-                let size = mem::size_of::<f32>();
-                let value = f32::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    /// returns index or count operand, fails if it is not an integer or negative,
+    /// an index that does not fit into usize becomes usize::MAX
+    fn get_index(&self, index: Value) -> Result<usize, VmError> {
+        match integer(self.get_immediate(index)) {
+            Some(index) if index >= 0 => Ok(usize::try_from(index).unwrap_or(usize::MAX)),
+            _ => Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+        }
+    }
 
-            9 => { // f64
-                self.ip += 1;
-                let size = mem::size_of::<f64>();
-                let value = f64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-                let ptr = Ptr::allocate(size);
-                ptr.set_data(value);
-                self.ip += size - 1;
-                ptr
-            }
+    /// sets value of input at index, fails if input would grow beyond its maximum length
+    fn set_input(&mut self, index: usize, value: Immediate) -> Result<Immediate, VmError> {
+        let limit = self.max_buffer_len;
+        self.input.set(index, value, limit)
+            .ok_or_else(|| VmError::BufferLimitExceeded { ip: self.op_ip(), opcode: self.opcode(), index, limit })
+    }
 
-            10 => { // bool
-                self.ip += 1;
-                let value = self.bytecode[self.ip] != 0;
-                let ptr = Ptr::allocate(mem::size_of::<bool>());
-                ptr.set_data(value);
-                ptr
-            }
+    /// sets value of output at index, fails if output would grow beyond its maximum length
+    fn set_output(&mut self, index: usize, value: Immediate) -> Result<Immediate, VmError> {
+        let limit = self.max_buffer_len;
+        self.output.set(index, value, limit)
+            .ok_or_else(|| VmError::BufferLimitExceeded { ip: self.op_ip(), opcode: self.opcode(), index, limit })
+    }
 
-            11 => { // maximum index of input
-                let mut index = self.input.len();
-                if index > 0 {
-                    index -= 1;
-                }
-                let ptr = Ptr::allocate(mem::size_of::<u64>());
-                ptr.set_data(index);
-                ptr
-            }
+    /// returns value that can be saved to heap, fails for NONE
+    fn get_scalar(&self, value: Value) -> Result<Immediate, VmError> {
+        match self.get_immediate(value) {
+            Immediate::NONE() => Err(VmError::TypeMismatch { ip: self.op_ip(), opcode: self.opcode() }),
+            value => Ok(value),
+        }
+    }
+}
 
-            12 => { // maximum index of output
-                let mut index = self.output.len();
-                if index > 0 {
-                    index -= 1;
-                }
-                let ptr = Ptr::allocate(mem::size_of::<u64>());
-                ptr.set_data(index);
-                ptr
-            }
+impl Drop for VM {
+    /// reports leaks, objects on heap are freed when the heap is dropped
+    fn drop(&mut self) {
+        self.report_leaks();
+    }
+}
 
-            _ => { Ptr::allocate(0) }
+/// adds blobs of constants to heap in order, returns values of constants where a blob
+/// is the address of its array of u8
+fn add_constants(heap: &mut Heap, constants: &[Constant]) -> Vec<Immediate> {
+    constants.iter().map(|constant| match constant {
+        Constant::Value(value) => *value,
+        Constant::Bytes(bytes) => {
+            Immediate::ADDRESS(heap.add(HeapObject::Bytes(bytes.clone()), None))
         }
-    }
+    }).collect()
+}
 
-    fn get_address(&mut self) -> Address {
-        self.ip += 1;
-        let size = mem::size_of::<u64>();
-        let value = u64::from_be_bytes(self.bytecode[self.ip..self.ip + size].try_into().unwrap());
-        self.ip += size - 1;
-        value as usize
+/// returns error of address that could not be used
+fn heap_error(error: HeapError, ip: usize, opcode: u8, address: Address) -> VmError {
+    match error {
+        HeapError::Invalid => VmError::InvalidHeapAddress { ip, opcode, address },
+        HeapError::Freed => VmError::UseAfterFree { ip, opcode, address },
     }
 }
+
+/// returns value of integer, None for floats, bools, addresses and NONE
+fn integer(value: Immediate) -> Option<i128> {
+    let value = match value {
+        Immediate::U8(v) => v as i128,
+        Immediate::U16(v) => v as i128,
+        Immediate::U32(v) => v as i128,
+        Immediate::U64(v) => v as i128,
+        Immediate::I8(v) => v as i128,
+        Immediate::I16(v) => v as i128,
+        Immediate::I32(v) => v as i128,
+        Immediate::I64(v) => v as i128,
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// returns addresses among values
+fn addresses(values: &[Immediate]) -> impl Iterator<Item = Address> + '_ {
+    values.iter().filter_map(|value| match value {
+        Immediate::ADDRESS(address) => Some(*address),
+        _ => None,
+    })
+}
@@ -0,0 +1,92 @@
+/// operand of an instruction as it is encoded in bytecode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// type tag followed by big endian value, see immediate_size, tag 13 is followed by
+    /// u16 index of a constant of the program
+    Immediate,
+    /// one byte type tag of a value, 0..=10
+    Type,
+    /// big endian u64
+    Address,
+}
+
+/// name and operands of an opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    pub name: &'static str,
+    pub operands: &'static [Operand],
+}
+
+const fn info(name: &'static str, operands: &'static [Operand]) -> OpcodeInfo {
+    OpcodeInfo { name, operands }
+}
+
+use Operand::*;
+
+/// opcodes indexed by their value, in the same order as instructions of VM
+pub const OPCODES: &[OpcodeInfo] = &[
+    info("nop", &[]),                           // 0
+    info("push", &[Immediate]),                 // 1
+    info("pop", &[]),                           // 2
+    info("popi", &[Immediate]),                 // 3
+    info("set", &[Immediate, Immediate]),       // 4
+    info("get", &[Immediate]),                  // 5
+    info("geti", &[Immediate, Immediate]),      // 6
+    info("clear_i", &[]),                       // 7
+    info("clear_o", &[]),                       // 8
+    info("gen", &[Type, Address]),              // 9
+    info("save", &[Immediate]),                 // 10
+    info("savei", &[Immediate, Immediate]),     // 11
+    info("load", &[Address, Type]),             // 12
+    info("loadi", &[Address, Immediate, Type]), // 13
+    info("less", &[]),                          // 14
+    info("great", &[]),                         // 15
+    info("eq", &[]),                            // 16
+    info("jmp", &[]),                           // 17
+    info("add", &[]),                           // 18
+    info("sub", &[]),                           // 19
+    info("mul", &[]),                           // 20
+    info("div", &[]),                           // 21
+    info("rem", &[]),                           // 22
+    info("neg", &[]),                           // 23
+    info("abs", &[]),                           // 24
+    info("jmp_if", &[]),                        // 25
+    info("jmp_unless", &[]),                    // 26
+    info("call", &[Immediate]),                 // 27
+    info("ret", &[Immediate]),                  // 28
+    info("halt", &[]),                          // 29
+    info("ovf", &[Immediate]),                  // 30
+    info("cast", &[Type]),                      // 31
+    info("castc", &[Type]),                     // 32
+    info("store", &[]),                         // 33
+    info("free", &[]),                          // 34
+    info("aload", &[]),                         // 35
+    info("astore", &[]),                        // 36
+    info("alen", &[]),                          // 37
+];
+
+/// names of value types indexed by their type tag
+pub const TYPES: &[&str] = &["u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64", "f32", "f64", "bool"];
+
+/// returns name and operands of opcode, None if opcode is unknown
+pub fn opcode_info(opcode: u8) -> Option<&'static OpcodeInfo> {
+    OPCODES.get(opcode as usize)
+}
+
+/// returns opcode with name
+pub fn opcode_by_name(name: &str) -> Option<u8> {
+    OPCODES.iter().position(|info| info.name == name).map(|opcode| opcode as u8)
+}
+
+/// returns number of bytes that follow type tag of immediate operand, None if tag is unknown
+pub fn immediate_size(tag: u8) -> Option<usize> {
+    match tag {
+        0 | 4 | 10 => Some(1), // u8, i8, bool
+        1 | 5 => Some(2), // u16, i16
+        2 | 6 | 8 => Some(4), // u32, i32, f32
+        3 | 7 | 9 => Some(8), // u64, i64, f64
+        11 | 12 => Some(0), // maximum index of input, maximum index of output
+        13 => Some(2), // index of constant
+        _ => None,
+    }
+}
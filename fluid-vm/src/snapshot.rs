@@ -0,0 +1,423 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::buffer::*;
+use crate::container::*;
+use crate::decoder::*;
+use crate::frame::*;
+use crate::gc::*;
+use crate::heap::*;
+use crate::tools::*;
+use crate::VM;
+
+/// first bytes of every snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"FLDS";
+
+/// version of the layout of a snapshot
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// error of taking or restoring a snapshot
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// file could not be read or written
+    Io(io::Error),
+    /// bytes do not start with SNAPSHOT_MAGIC
+    BadMagic,
+    /// snapshot was written in a format this VM can not read
+    UnsupportedVersion(u16),
+    /// program of snapshot could not be written or read
+    Program(ContainerError),
+    /// section runs past the end of bytes or ends before its contents
+    Truncated,
+    /// bytes follow the last section or the contents of a section
+    TrailingBytes,
+    /// value has an unknown type tag or heap object an unknown kind
+    InvalidValue,
+    /// ip is not at an instruction or slots of heap do not fit together
+    InvalidState,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{}", error),
+            SnapshotError::BadMagic => write!(f, "not a fluid snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}, this VM reads version {}", version, SNAPSHOT_VERSION)
+            }
+            SnapshotError::Program(error) => write!(f, "program of snapshot: {}", error),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::TrailingBytes => write!(f, "unexpected bytes after the end of a section"),
+            SnapshotError::InvalidValue => write!(f, "invalid value in snapshot"),
+            SnapshotError::InvalidState => write!(f, "inconsistent state in snapshot"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(error) => Some(error),
+            SnapshotError::Program(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+impl From<ContainerError> for SnapshotError {
+    fn from(error: ContainerError) -> Self {
+        SnapshotError::Program(error)
+    }
+}
+
+impl From<Fault> for SnapshotError {
+    fn from(_: Fault) -> Self {
+        SnapshotError::Truncated
+    }
+}
+
+/// state of a VM that is saved by snapshot and restored by restore
+///
+/// layout, every number is big endian:
+///
+/// ```text
+/// magic           4 bytes  "FLDS"
+/// version         u16
+/// program         u32 length, container without debug section
+/// state           u32 length, state of execution
+/// heap            u32 length, slots of heap
+/// ```
+///
+/// state is u64 ip, u64 executed instructions, u8 overflow mode, u64 maximum call depth, u64 maximum length of buffers, u8 reference
+/// counting, u64 max_bytes, max_objects and max_allocation of limits, u8 flag and u64 gc threshold,
+/// u64 allocations, collections, freed and live of gc, values of input and output, u32 count of
+/// frames and for every frame u64 return ip and values of its input and output
+///
+/// values are u32 count and tagged values, a tagged value is type tag 0..=10 followed by a value
+/// encoded like an operand of push, tag 11 followed by u32 index and u32 generation of an address
+/// or tag 12 of NONE
+///
+/// heap is u64 peak bytes, u64 allocated, u64 freed, u32 count of slots, slots, u32 count and u32
+/// indexes of empty slots, a slot is u32 generation, u32 references, u8 flag and u64 allocation ip
+/// and kind 0 of an empty slot, kind 1 followed by a tagged value, kind 2 followed by type tag,
+/// u64 length, elements encoded without tag, u64 count and u64 indexes of elements stored as an
+/// address or kind 3 followed by u32 length and bytes of a blob
+///
+/// the leak report is not saved, it has to be set again after restore
+impl VM {
+    /// returns snapshot of VM, fails if a constant can not be stored, execution can be continued
+    /// after the snapshot is restored by restore
+    pub fn snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let program = Container {
+            code: self.bytecode.clone(),
+            constants: self.constants.clone(),
+            debug: None,
+        };
+
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend(SNAPSHOT_VERSION.to_be_bytes());
+        write_section(&mut bytes, &program.write()?);
+        write_section(&mut bytes, &self.write_state());
+        write_section(&mut bytes, &write_heap(&self.heap));
+        Ok(bytes)
+    }
+
+    /// returns VM that continues from state of snapshot
+    pub fn restore(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytecode: bytes, offset: 0 };
+        if reader.bytes::<4>().map_err(|_| SnapshotError::BadMagic)? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = u16::from_be_bytes(reader.bytes()?);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let program = Container::read(section(&mut reader)?)?;
+        let state = section(&mut reader)?;
+        let heap = read_heap(section(&mut reader)?)?;
+        if reader.offset != bytes.len() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+
+        let mut vm = VM::from_container(program);
+        vm.read_state(state)?;
+        vm.heap = heap;
+        Ok(vm)
+    }
+
+    /// writes snapshot of VM to file
+    pub fn snapshot_file(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        fs::write(path, self.snapshot()?)?;
+        Ok(())
+    }
+
+    /// restores VM from snapshot in file
+    pub fn restore_file(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        VM::restore(&fs::read(path)?)
+    }
+
+    fn write_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_u64(&mut bytes, self.ip());
+        bytes.extend(self.executed.to_be_bytes());
+        bytes.push(match self.overflow {
+            Overflow::Trap => 0,
+            Overflow::Wrap => 1,
+            Overflow::Saturate => 2,
+        });
+        write_u64(&mut bytes, self.max_call_depth);
+        write_u64(&mut bytes, self.max_buffer_len);
+        bytes.push(self.reference_counting as u8);
+        write_u64(&mut bytes, self.limits.max_bytes);
+        write_u64(&mut bytes, self.limits.max_objects);
+        write_u64(&mut bytes, self.limits.max_allocation);
+        bytes.push(self.gc.threshold.is_some() as u8);
+        write_u64(&mut bytes, self.gc.threshold.unwrap_or(0));
+        write_u64(&mut bytes, self.gc.allocations);
+        bytes.extend(self.gc.stats.collections.to_be_bytes());
+        bytes.extend(self.gc.stats.freed.to_be_bytes());
+        write_u64(&mut bytes, self.gc.stats.live);
+        write_values(&mut bytes, self.input.as_slice());
+        write_values(&mut bytes, self.output.as_slice());
+
+        bytes.extend((self.frames.len() as u32).to_be_bytes());
+        for frame in &self.frames {
+            write_u64(&mut bytes, self.program.offsets[frame.return_pc]);
+            write_values(&mut bytes, frame.input.as_slice());
+            write_values(&mut bytes, frame.output.as_slice());
+        }
+
+        bytes
+    }
+
+    fn read_state(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader { bytecode: bytes, offset: 0 };
+        self.pc = self.index_of(read_u64(&mut reader)?)?;
+        self.executed = u64::from_be_bytes(reader.bytes()?);
+        self.overflow = match reader.byte()? {
+            0 => Overflow::Trap,
+            1 => Overflow::Wrap,
+            2 => Overflow::Saturate,
+            _ => return Err(SnapshotError::InvalidState),
+        };
+        self.max_call_depth = read_u64(&mut reader)?;
+        self.max_buffer_len = read_u64(&mut reader)?;
+        self.reference_counting = reader.byte()? != 0;
+        self.limits = HeapLimits {
+            max_bytes: read_u64(&mut reader)?,
+            max_objects: read_u64(&mut reader)?,
+            max_allocation: read_u64(&mut reader)?,
+        };
+        let has_threshold = reader.byte()? != 0;
+        let threshold = read_u64(&mut reader)?;
+        self.gc = Gc {
+            threshold: has_threshold.then_some(threshold),
+            allocations: read_u64(&mut reader)?,
+            stats: GcStats {
+                collections: u64::from_be_bytes(reader.bytes()?),
+                freed: u64::from_be_bytes(reader.bytes()?),
+                live: read_u64(&mut reader)?,
+            },
+        };
+        self.input = Buffer::from_values(read_values(&mut reader)?);
+        self.output = Buffer::from_values(read_values(&mut reader)?);
+
+        let count = u32::from_be_bytes(reader.bytes()?);
+        self.frames = Vec::new();
+        for _ in 0..count {
+            self.frames.push(Frame {
+                return_pc: self.index_of(read_u64(&mut reader)?)?,
+                input: Buffer::from_values(read_values(&mut reader)?),
+                output: Buffer::from_values(read_values(&mut reader)?),
+            });
+        }
+
+        if reader.offset != bytes.len() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+
+        Ok(())
+    }
+
+    /// returns index of instruction at ip, fails if ip is inside of an instruction or past the end of bytecode
+    fn index_of(&self, ip: usize) -> Result<usize, SnapshotError> {
+        self.program.index_of(ip).ok_or(SnapshotError::InvalidState)
+    }
+}
+
+fn write_u64(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend((value as u64).to_be_bytes());
+}
+
+/// reads u64 that has to fit into usize
+fn read_u64(reader: &mut Reader) -> Result<usize, SnapshotError> {
+    usize::try_from(u64::from_be_bytes(reader.bytes()?)).map_err(|_| SnapshotError::InvalidState)
+}
+
+fn write_value(bytes: &mut Vec<u8>, value: Immediate) {
+    match value {
+        Immediate::ADDRESS(address) => {
+            bytes.push(11);
+            bytes.extend(address.index.to_be_bytes());
+            bytes.extend(address.generation.to_be_bytes());
+        }
+        Immediate::NONE() => bytes.push(12),
+        value => bytes.extend(value.encode().expect("values of type 0..=10 can be encoded")),
+    }
+}
+
+/// reads value of type tag without its tag
+fn read_value_of(reader: &mut Reader, tag: u8) -> Result<Immediate, SnapshotError> {
+    let value = match tag {
+        0 => Immediate::U8(reader.byte()?),
+        1 => Immediate::U16(u16::from_be_bytes(reader.bytes()?)),
+        2 => Immediate::U32(u32::from_be_bytes(reader.bytes()?)),
+        3 => Immediate::U64(u64::from_be_bytes(reader.bytes()?)),
+        4 => Immediate::I8(reader.byte()? as i8),
+        5 => Immediate::I16(i16::from_be_bytes(reader.bytes()?)),
+        6 => Immediate::I32(i32::from_be_bytes(reader.bytes()?)),
+        7 => Immediate::I64(i64::from_be_bytes(reader.bytes()?)),
+        8 => Immediate::F32(f32::from_be_bytes(reader.bytes()?)),
+        9 => Immediate::F64(f64::from_be_bytes(reader.bytes()?)),
+        10 => Immediate::BOOL(reader.byte()? != 0),
+        11 => Immediate::ADDRESS(Address::new(u32::from_be_bytes(reader.bytes()?), u32::from_be_bytes(reader.bytes()?))),
+        12 => Immediate::NONE(),
+        _ => return Err(SnapshotError::InvalidValue),
+    };
+
+    Ok(value)
+}
+
+fn read_value(reader: &mut Reader) -> Result<Immediate, SnapshotError> {
+    let tag = reader.byte()?;
+    read_value_of(reader, tag)
+}
+
+fn write_values(bytes: &mut Vec<u8>, values: &[Immediate]) {
+    bytes.extend((values.len() as u32).to_be_bytes());
+    for &value in values {
+        write_value(bytes, value);
+    }
+}
+
+fn read_values(reader: &mut Reader) -> Result<Vec<Immediate>, SnapshotError> {
+    let count = u32::from_be_bytes(reader.bytes()?);
+    (0..count).map(|_| read_value(reader)).collect()
+}
+
+fn write_heap(heap: &Heap) -> Vec<u8> {
+    let stats = heap.stats();
+    let mut bytes = Vec::new();
+    write_u64(&mut bytes, stats.peak_bytes);
+    bytes.extend(stats.allocated.to_be_bytes());
+    bytes.extend(stats.freed.to_be_bytes());
+
+    bytes.extend((heap.slot_data().len() as u32).to_be_bytes());
+    for slot in heap.slot_data() {
+        bytes.extend(slot.generation.to_be_bytes());
+        bytes.extend(slot.count.to_be_bytes());
+        bytes.push(slot.ip.is_some() as u8);
+        write_u64(&mut bytes, slot.ip.unwrap_or(0));
+
+        match &slot.object {
+            None => bytes.push(0),
+            Some(HeapObject::Scalar(value)) => {
+                bytes.push(1);
+                write_value(&mut bytes, *value);
+            }
+            Some(HeapObject::Array(array)) => {
+                bytes.push(2);
+                bytes.push(array.element_type());
+                write_u64(&mut bytes, array.len());
+                for index in 0..array.len() {
+                    bytes.extend(&array.get(index).encode().expect("elements can be encoded")[1..]);
+                }
+                write_u64(&mut bytes, array.addresses().count());
+                for (index, _) in array.addresses() {
+                    write_u64(&mut bytes, index);
+                }
+            }
+            Some(HeapObject::Bytes(blob)) => {
+                bytes.push(3);
+                write_section(&mut bytes, blob);
+            }
+        }
+    }
+
+    bytes.extend((heap.empty().len() as u32).to_be_bytes());
+    for index in heap.empty() {
+        bytes.extend(index.to_be_bytes());
+    }
+
+    bytes
+}
+
+fn read_heap(bytes: &[u8]) -> Result<Heap, SnapshotError> {
+    let mut reader = Reader { bytecode: bytes, offset: 0 };
+    let peak_bytes = read_u64(&mut reader)?;
+    let allocated = u64::from_be_bytes(reader.bytes()?);
+    let freed = u64::from_be_bytes(reader.bytes()?);
+
+    let count = u32::from_be_bytes(reader.bytes()?);
+    let mut slots = Vec::new();
+    for _ in 0..count {
+        let generation = u32::from_be_bytes(reader.bytes()?);
+        let count = u32::from_be_bytes(reader.bytes()?);
+        let has_ip = reader.byte()? != 0;
+        let ip = read_u64(&mut reader)?;
+
+        let object = match reader.byte()? {
+            0 => None,
+            1 => Some(HeapObject::Scalar(read_value(&mut reader)?)),
+            2 => Some(HeapObject::Array(read_array(&mut reader)?)),
+            3 => Some(HeapObject::Bytes(section(&mut reader)?.to_vec())),
+            _ => return Err(SnapshotError::InvalidValue),
+        };
+        slots.push(Slot { generation, count, ip: has_ip.then_some(ip), object });
+    }
+
+    let count = u32::from_be_bytes(reader.bytes()?);
+    let empty = (0..count).map(|_| reader.bytes().map(u32::from_be_bytes)).collect::<Result<Vec<_>, _>>()?;
+    if reader.offset != bytes.len() {
+        return Err(SnapshotError::TrailingBytes);
+    }
+
+    Heap::from_slots(slots, empty, peak_bytes, allocated, freed).ok_or(SnapshotError::InvalidState)
+}
+
+/// reads array, its length is checked against remaining bytes before it is allocated
+fn read_array(reader: &mut Reader) -> Result<Array, SnapshotError> {
+    let tag = reader.byte()?;
+    let size = type_size(tag).ok_or(SnapshotError::InvalidValue)?;
+    let length = read_u64(reader)?;
+    let remaining = reader.bytecode.len() - reader.offset;
+    if !matches!(length.checked_mul(size), Some(size) if size <= remaining) {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let mut array = Array::new(tag, length).expect("type tag was checked");
+    for index in 0..length {
+        let value = read_value_of(reader, tag)?;
+        array.set(index, value);
+    }
+
+    for _ in 0..read_u64(reader)? {
+        let index = read_u64(reader)?;
+        let value = match (index < length).then(|| array.get(index)) {
+            Some(Immediate::U64(v)) => Immediate::ADDRESS(Address::from_u64(v)),
+            _ => return Err(SnapshotError::InvalidValue),
+        };
+        array.set(index, value);
+    }
+    Ok(array)
+}
@@ -0,0 +1,1377 @@
+use crate::*;
+use crate::decoder::*;
+
+fn run(bytecode: Vec<u8>) -> VM {
+    let mut vm = VM::new(bytecode);
+    vm.execute().unwrap();
+    vm
+}
+
+#[test]
+fn add_pushes_sum_to_output() {
+    // push u8 3, push u8 4, add
+    let vm = run(vec![1, 0, 3, 1, 0, 4, 18]);
+    assert_eq!(vm.output.get(0), Immediate::U8(7));
+    assert_eq!(vm.input.len(), 0);
+}
+
+#[test]
+fn sub_uses_last_pushed_value_as_left_operand() {
+    // push i16 3, push i16 10, sub
+    let vm = run(vec![1, 5, 0, 3, 1, 5, 0, 10, 19]);
+    assert_eq!(vm.output.get(0), Immediate::I16(7));
+}
+
+#[test]
+fn mul_div_rem() {
+    // push u32 6, push u32 7, mul
+    let vm = run(vec![1, 2, 0, 0, 0, 6, 1, 2, 0, 0, 0, 7, 20]);
+    assert_eq!(vm.output.get(0), Immediate::U32(42));
+
+    // push i64 4, push i64 17, div
+    let mut bytecode = vec![1, 7];
+    bytecode.extend(4i64.to_be_bytes());
+    bytecode.extend([1, 7]);
+    bytecode.extend(17i64.to_be_bytes());
+    bytecode.push(21);
+    let vm = run(bytecode);
+    assert_eq!(vm.output.get(0), Immediate::I64(4));
+
+    // push u8 4, push u8 17, rem
+    let vm = run(vec![1, 0, 4, 1, 0, 17, 22]);
+    assert_eq!(vm.output.get(0), Immediate::U8(1));
+}
+
+#[test]
+fn neg_and_abs() {
+    // push i8 5, neg, pop, abs
+    let vm = run(vec![1, 4, 5, 23, 2, 24]);
+    assert_eq!(vm.output.get(0), Immediate::I8(5));
+
+    // push f32 -1.5, abs
+    let mut bytecode = vec![1, 8];
+    bytecode.extend((-1.5f32).to_be_bytes());
+    bytecode.push(24);
+    let vm = run(bytecode);
+    assert_eq!(vm.output.get(0), Immediate::F32(1.5));
+}
+
+#[test]
+fn results_feed_further_arithmetic() {
+    // (2 + 3) * 4: push u8 2, push u8 3, add, pop, push u8 4, mul
+    let vm = run(vec![1, 0, 2, 1, 0, 3, 18, 2, 1, 0, 4, 20]);
+    assert_eq!(vm.output.get(0), Immediate::U8(20));
+    assert_eq!(vm.output.len(), 1);
+}
+
+#[test]
+fn mixed_types_are_type_mismatch() {
+    // push u8 1, push u16 1, add
+    let mut vm = VM::new(vec![1, 0, 1, 1, 1, 0, 1, 18]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 7, opcode: 18 }));
+}
+
+#[test]
+fn neg_of_unsigned_is_type_mismatch() {
+    let mut vm = VM::new(vec![1, 0, 1, 23]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 3, opcode: 23 }));
+}
+
+#[test]
+fn arithmetic_on_empty_input_underflows() {
+    let mut vm = VM::new(vec![1, 0, 1, 18]);
+    assert_eq!(vm.execute(), Err(VmError::StackUnderflow { ip: 3, opcode: 18 }));
+}
+
+#[test]
+fn unknown_opcode_reports_ip_and_opcode() {
+    // nop, push u8 1, opcode 250
+    let error = VM::new(vec![0, 1, 0, 1, 250]).execute().unwrap_err();
+    assert_eq!(error, VmError::UnknownOpcode { ip: 4, opcode: 250 });
+    assert_eq!((error.ip(), error.opcode()), (4, 250));
+    assert_eq!(error.to_string(), "unknown opcode (opcode 250 at ip 4)");
+}
+
+#[test]
+fn truncated_operand_reports_ip_and_opcode() {
+    // nop, push u32 with two of four bytes
+    let error = VM::new(vec![0, 1, 2, 0, 0]).execute().unwrap_err();
+    assert_eq!(error, VmError::TruncatedOperand { ip: 1, opcode: 1 });
+    assert_eq!((error.ip(), error.opcode()), (1, 1));
+    assert_eq!(error.to_string(), "operand runs past the end of bytecode (opcode 1 at ip 1)");
+}
+
+#[test]
+fn stack_underflow_reports_ip_and_opcode() {
+    // nop, pop
+    let error = VM::new(vec![0, 2]).execute().unwrap_err();
+    assert_eq!(error, VmError::StackUnderflow { ip: 1, opcode: 2 });
+    assert_eq!((error.ip(), error.opcode()), (1, 2));
+    assert_eq!(error.to_string(), "pop from an empty buffer (opcode 2 at ip 1)");
+}
+
+#[test]
+fn invalid_heap_address_reports_ip_and_opcode() {
+    // nop, load 5 as u8
+    let mut bytecode = vec![0, 12];
+    bytecode.extend(5u64.to_be_bytes());
+    bytecode.push(0);
+    let error = VM::new(bytecode).execute().unwrap_err();
+    assert_eq!(error, VmError::InvalidHeapAddress { ip: 1, opcode: 12, address: Address::new(5, 0) });
+    assert_eq!((error.ip(), error.opcode()), (1, 12));
+    assert_eq!(error.to_string(), "invalid heap address 5 (opcode 12 at ip 1)");
+}
+
+#[test]
+fn bool_arithmetic_fails_instead_of_panicking() {
+    // push bool true, push bool false, sub
+    let mut vm = VM::new(vec![1, 10, 1, 1, 10, 0, 19]);
+    assert_eq!(vm.execute(), Err(VmError::ArithmeticOverflow { ip: 6, opcode: 19 }));
+
+    // ovf u8 1, push bool true, push bool false, sub
+    let vm = run(vec![30, 0, 1, 1, 10, 1, 1, 10, 0, 19]);
+    assert_eq!(vm.output(), &[Immediate::BOOL(false)]);
+}
+
+#[test]
+fn jmp_if_builds_a_loop() {
+    // counts from 0 to 5
+    let vm = run(vec![
+        1, 0, 0,    // 0: push u8 0
+        1, 0, 1,    // 3: push u8 1
+        18,         // 6: add
+        5, 0, 0,    // 7: get u8 0
+        5, 0, 0,    // 10: get u8 0
+        1, 0, 5,    // 13: push u8 5
+        15,         // 16: great
+        2,          // 17: pop
+        1, 0, 3,    // 18: push u8 3
+        8,          // 21: clear_o
+        25,         // 22: jmp_if
+    ]);
+    assert_eq!(vm.input.get(0), Immediate::U8(5));
+    assert_eq!(vm.input.len(), 1);
+}
+
+#[test]
+fn jmp_unless_skips_branch() {
+    let bytecode = |condition| vec![
+        1, 10, condition,   // 0: push bool
+        1, 0, 10,           // 3: push u8 10
+        26,                 // 6: jmp_unless
+        1, 0, 1,            // 7: push u8 1
+        0,                  // 10: nop
+    ];
+
+    let vm = run(bytecode(1));
+    assert_eq!(vm.input.get(0), Immediate::U8(1));
+    let vm = run(bytecode(0));
+    assert_eq!(vm.input.len(), 0);
+}
+
+#[test]
+fn conditional_jump_needs_bool() {
+    let mut vm = VM::new(vec![1, 0, 1, 1, 0, 0, 25]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 25 }));
+}
+
+#[test]
+fn call_passes_arguments_and_returns_results() {
+    let vm = run(vec![
+        1, 0, 21,   // 0: push u8 21
+        1, 0, 14,   // 3: push u8 14
+        27, 0, 1,   // 6: call u8 1
+        2,          // 9: pop
+        1, 0, 21,   // 10: push u8 21
+        17,         // 13: jmp
+        1, 0, 2,    // 14: push u8 2
+        20,         // 17: mul
+        28, 0, 1,   // 18: ret u8 1
+    ]);
+    assert_eq!(vm.input.get(0), Immediate::U8(42));
+    assert_eq!(vm.input.len(), 1);
+    assert!(vm.frames.is_empty());
+}
+
+#[test]
+fn recursion_stops_at_max_call_depth() {
+    let mut vm = VM::new(vec![1, 0, 0, 27, 0, 0]);
+    vm.set_max_call_depth(8);
+    assert_eq!(vm.execute(), Err(VmError::CallDepthExceeded { ip: 3, opcode: 27, depth: 8 }));
+    assert_eq!(vm.frames.len(), 8);
+}
+
+#[test]
+fn ret_outside_call_fails() {
+    let mut vm = VM::new(vec![28, 0, 0]);
+    assert_eq!(vm.execute(), Err(VmError::ReturnWithoutCall { ip: 0, opcode: 28 }));
+
+    // save u8 1, ret u8 1 keeps output
+    let mut vm = VM::new(vec![10, 0, 1, 28, 0, 1]);
+    assert_eq!(vm.execute(), Err(VmError::ReturnWithoutCall { ip: 3, opcode: 28 }));
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 0))]);
+}
+
+#[test]
+fn failed_call_leaves_input_unchanged() {
+    // push u8 7, push u8 0, call u8 1 at maximum call depth
+    let mut vm = VM::new(vec![1, 0, 7, 1, 0, 0, 27, 0, 1]);
+    vm.set_max_call_depth(0);
+    assert_eq!(vm.execute(), Err(VmError::CallDepthExceeded { ip: 6, opcode: 27, depth: 0 }));
+    assert_eq!(vm.input(), &[Immediate::U8(7), Immediate::U8(0)]);
+
+    // push u8 7, push u8 1, call u8 1 into the middle of push
+    let mut vm = VM::new(vec![1, 0, 7, 1, 0, 1, 27, 0, 1]);
+    assert_eq!(vm.execute(), Err(VmError::InvalidJumpTarget { ip: 6, opcode: 27, target: 1 }));
+    assert_eq!(vm.input(), &[Immediate::U8(7), Immediate::U8(1)]);
+    assert_eq!(vm.call_depth(), 0);
+
+    // push u8 7, push u8 0, call u8 2 with one argument
+    let mut vm = VM::new(vec![1, 0, 7, 1, 0, 0, 27, 0, 2]);
+    assert_eq!(vm.execute(), Err(VmError::StackUnderflow { ip: 6, opcode: 27 }));
+    assert_eq!(vm.input(), &[Immediate::U8(7), Immediate::U8(0)]);
+}
+
+#[test]
+fn halt_reports_exit_value() {
+    // push u8 3, push i32 -2, halt, push u8 1
+    let mut vm = VM::new(vec![1, 0, 3, 1, 6, 255, 255, 255, 254, 29, 1, 0, 1]);
+    let state = vm.execute().unwrap();
+    assert_eq!(state, ExitState { status: ExitStatus::Halted(Some(Immediate::I32(-2))), ip: 9, instructions: 3 });
+    assert_eq!(vm.input.len(), 1);
+}
+
+#[test]
+fn halt_without_value_and_running_off_the_end() {
+    let mut vm = VM::new(vec![0, 29]);
+    let state = vm.execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(None));
+
+    let mut vm = VM::new(vec![0, 0]);
+    let state = vm.execute().unwrap();
+    assert_eq!(state, ExitState { status: ExitStatus::Finished, ip: 2, instructions: 2 });
+}
+
+#[test]
+fn overflow_modes() {
+    // ovf u8 mode, push u8 1, push u8 255, add
+    let program = |mode| vec![30, 0, mode, 1, 0, 1, 1, 0, 255, 18];
+
+    let mut vm = VM::new(program(0));
+    assert_eq!(vm.execute(), Err(VmError::ArithmeticOverflow { ip: 9, opcode: 18 }));
+    let vm = run(program(1));
+    assert_eq!(vm.output.get(0), Immediate::U8(0));
+    let vm = run(program(2));
+    assert_eq!(vm.output.get(0), Immediate::U8(255));
+
+    let mut vm = VM::new(vec![30, 0, 3]);
+    assert_eq!(vm.execute(), Err(VmError::InvalidOperand { ip: 0, opcode: 30 }));
+
+    // ovf f32 1.9, ovf bool true, ovf u64 4294967297
+    for bytecode in [vec![30, 8, 0x33, 0x33, 0xf3, 0x3f], vec![30, 10, 1], vec![30, 3, 1, 0, 0, 0, 1, 0, 0, 0]] {
+        let mut vm = VM::new(bytecode);
+        assert_eq!(vm.execute(), Err(VmError::InvalidOperand { ip: 0, opcode: 30 }));
+    }
+}
+
+#[test]
+fn signed_overflow_of_neg_and_div() {
+    // push i8 -128, neg
+    let mut vm = VM::new(vec![1, 4, 128, 23]);
+    assert_eq!(vm.execute(), Err(VmError::ArithmeticOverflow { ip: 3, opcode: 23 }));
+
+    // ovf u8 2, push i8 -1, push i8 -128, div
+    let vm = run(vec![30, 0, 2, 1, 4, 255, 1, 4, 128, 21]);
+    assert_eq!(vm.output.get(0), Immediate::I8(127));
+}
+
+#[test]
+fn division_by_zero_traps_in_every_mode() {
+    for mode in 0..3 {
+        // ovf u8 mode, push u16 0, push u16 7, div
+        let mut vm = VM::new(vec![30, 0, mode, 1, 1, 0, 0, 1, 1, 0, 7, 21]);
+        assert_eq!(vm.execute(), Err(VmError::DivisionByZero { ip: 11, opcode: 21 }));
+    }
+
+    // push u8 0, push u8 7, rem
+    let mut vm = VM::new(vec![1, 0, 0, 1, 0, 7, 22]);
+    assert_eq!(vm.execute(), Err(VmError::DivisionByZero { ip: 6, opcode: 22 }));
+}
+
+#[test]
+fn arithmetic_overflow_traps_by_default() {
+    let fails = |bytecode: Vec<u8>| VM::new(bytecode).execute().unwrap_err();
+
+    // push u8 1, push u8 255, add
+    assert_eq!(fails(vec![1, 0, 1, 1, 0, 255, 18]), VmError::ArithmeticOverflow { ip: 6, opcode: 18 });
+    // push u8 1, push u8 0, sub
+    assert_eq!(fails(vec![1, 0, 1, 1, 0, 0, 19]), VmError::ArithmeticOverflow { ip: 6, opcode: 19 });
+    // push u8 16, push u8 16, mul
+    assert_eq!(fails(vec![1, 0, 16, 1, 0, 16, 20]), VmError::ArithmeticOverflow { ip: 6, opcode: 20 });
+    // push i8 -1, push i8 -128, rem
+    assert_eq!(fails(vec![1, 4, 255, 1, 4, 128, 22]), VmError::ArithmeticOverflow { ip: 6, opcode: 22 });
+    // push i8 -128, abs
+    assert_eq!(fails(vec![1, 4, 128, 24]), VmError::ArithmeticOverflow { ip: 3, opcode: 24 });
+}
+
+#[test]
+fn arithmetic_on_addresses_is_type_mismatch() {
+    // 0: save u8 1, 3: save u8 2, 6: get u8 0, 9: get u8 1, 12: rem
+    let mut vm = VM::new(vec![10, 0, 1, 10, 0, 2, 5, 0, 0, 5, 0, 1, 22]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 12, opcode: 22 }));
+
+    // 0: save u8 1, 3: get u8 0, 6: neg
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 23]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 23 }));
+}
+
+#[test]
+fn cast_converts_between_types() {
+    let cast = |value: Immediate, tag| value.cast(tag, false).unwrap();
+    assert_eq!(cast(Immediate::U16(0x1234), 0), Immediate::U8(0x34));
+    assert_eq!(cast(Immediate::I8(-1), 1), Immediate::U16(0xffff));
+    assert_eq!(cast(Immediate::I8(-1), 7), Immediate::I64(-1));
+    assert_eq!(cast(Immediate::F64(-3.9), 6), Immediate::I32(-3));
+    assert_eq!(cast(Immediate::F32(1e10), 5), Immediate::I16(i16::MAX));
+    assert_eq!(cast(Immediate::F64(f64::NAN), 3), Immediate::U64(0));
+    assert_eq!(cast(Immediate::BOOL(true), 8), Immediate::F32(1.0));
+    assert_eq!(cast(Immediate::U32(7), 10), Immediate::BOOL(true));
+    assert_eq!(cast(Immediate::ADDRESS(Address::new(5, 0)), 4), Immediate::I8(5));
+
+    // push u16 300, cast u8, pop, cast f64
+    let vm = run(vec![1, 1, 1, 44, 31, 0, 2, 31, 9]);
+    assert_eq!(vm.output.get(0), Immediate::F64(44.0));
+}
+
+#[test]
+fn checked_cast_traps_when_value_does_not_fit() {
+    let cast = |value: Immediate, tag| value.cast(tag, true);
+    assert_eq!(cast(Immediate::U16(255), 0), Ok(Immediate::U8(255)));
+    assert_eq!(cast(Immediate::I8(-1), 0), Err(ArithmeticError::Overflow));
+    assert_eq!(cast(Immediate::F64(2.5), 4), Ok(Immediate::I8(2)));
+    assert_eq!(cast(Immediate::F64(f64::INFINITY), 7), Err(ArithmeticError::Overflow));
+    assert_eq!(cast(Immediate::F64(1e300), 8), Err(ArithmeticError::Overflow));
+    assert_eq!(cast(Immediate::F64(f64::INFINITY), 8), Ok(Immediate::F32(f32::INFINITY)));
+
+    // push u16 300, castc u8
+    let mut vm = VM::new(vec![1, 1, 1, 44, 32, 0]);
+    assert_eq!(vm.execute(), Err(VmError::CastOutOfRange { ip: 4, opcode: 32 }));
+    let mut vm = VM::new(vec![1, 0, 1, 31, 11]);
+    assert_eq!(vm.execute(), Err(VmError::InvalidTypeTag { ip: 3, opcode: 31, tag: 11 }));
+}
+
+#[test]
+fn fuel_limits_and_resumes_execution() {
+    // push u8 0, jmp, loops forever
+    let mut vm = VM::new(vec![1, 0, 0, 17]);
+    let state = vm.execute_with_fuel(5).unwrap();
+    assert_eq!(state, ExitState { status: ExitStatus::OutOfFuel, ip: 3, instructions: 5 });
+    let state = vm.execute_with_fuel(1).unwrap();
+    assert_eq!(state, ExitState { status: ExitStatus::OutOfFuel, ip: 0, instructions: 6 });
+
+    // push u8 1, push u8 2, add, halt
+    let mut vm = VM::new(vec![1, 0, 1, 1, 0, 2, 18, 29]);
+    assert_eq!(vm.execute_with_fuel(2).unwrap().status, ExitStatus::OutOfFuel);
+    assert_eq!(vm.execute_with_fuel(1).unwrap().ip, 7);
+    let state = vm.execute_with_fuel(1).unwrap();
+    assert_eq!(state, ExitState { status: ExitStatus::Halted(None), ip: 7, instructions: 4 });
+    assert_eq!(vm.output.get(0), Immediate::U8(3));
+}
+
+#[test]
+fn step_and_run_until() {
+    // 0: push u8 1, 3: push u8 2, 6: add, 7: save u8 9, 10: halt
+    let mut vm = VM::new(vec![1, 0, 1, 1, 0, 2, 18, 10, 0, 9, 29]);
+    let state = vm.step().unwrap();
+    assert_eq!(state, ExitState { status: ExitStatus::Paused, ip: 3, instructions: 1 });
+    assert_eq!(vm.input(), &[Immediate::U8(1)]);
+
+    let state = vm.run_until(7).unwrap();
+    assert_eq!(state.status, ExitStatus::Paused);
+    assert_eq!(vm.ip(), 7);
+    assert_eq!(vm.input(), &[]);
+    assert_eq!(vm.output(), &[Immediate::U8(3)]);
+    assert_eq!(vm.heap_entries().count(), 0);
+
+    vm.step().unwrap();
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(vm.step().unwrap().status, ExitStatus::Halted(None));
+    assert_eq!(vm.call_depth(), 0);
+
+    let mut vm = VM::new(vec![0]);
+    assert_eq!(vm.step().unwrap().status, ExitStatus::Finished);
+    assert_eq!(vm.step().unwrap().status, ExitStatus::Finished);
+}
+
+#[test]
+fn instructions_match_opcode_table() {
+    for (opcode, info) in OPCODES.iter().enumerate() {
+        let mut bytecode = vec![opcode as u8];
+        for operand in info.operands {
+            match operand {
+                Operand::Immediate => bytecode.extend([0, 0]),
+                Operand::Type => bytecode.push(0),
+                Operand::Address => bytecode.extend([0; 8]),
+            }
+        }
+
+        let (instruction, next) = decode_at(&bytecode, 0).unwrap();
+        assert_eq!(next, bytecode.len(), "{}", info.name);
+        let variant = format!("{:?}", instruction);
+        let variant = variant.split('(').next().unwrap().to_lowercase();
+        assert_eq!(variant, info.name.replace('_', ""), "opcode {}", opcode);
+    }
+
+    assert_eq!(decode_at(&[OPCODES.len() as u8], 0), Err(Fault::UnknownOpcode));
+}
+
+#[test]
+fn execute_decoding_matches_execute() {
+    let mut container = Container::new(vec![
+        1, 13, 0, 0,    // 0: push #0
+        1, 0, 2,        // 4: push u8 2
+        18,             // 7: add
+        1, 0, 15,       // 8: push u8 15
+        17,             // 11: jmp
+        0,              // 12: nop
+        0,              // 13: nop
+        0,              // 14: nop
+        5, 0, 0,        // 15: get u8 0
+        29,             // 18: halt
+    ]);
+    container.constants.push(Constant::Value(Immediate::U8(40)));
+
+    let state = VM::from_container(container.clone()).execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(Some(Immediate::U8(42))));
+    assert_eq!(VM::from_container(container).execute_decoding().unwrap(), state);
+    assert_eq!(VM::new(vec![1, 0]).execute_decoding(), Err(VmError::TruncatedOperand { ip: 0, opcode: 1 }));
+}
+
+#[test]
+fn jump_into_instruction_fails() {
+    // push u8 1, jmp
+    let mut vm = VM::new(vec![1, 0, 1, 17]);
+    assert_eq!(vm.execute(), Err(VmError::InvalidJumpTarget { ip: 3, opcode: 17, target: 1 }));
+    assert_eq!(vm.ip(), 3);
+
+    // push u8 4, jmp, jumping to the end finishes
+    let mut vm = VM::new(vec![1, 0, 4, 17]);
+    assert_eq!(vm.execute().unwrap(), ExitState { status: ExitStatus::Finished, ip: 4, instructions: 2 });
+}
+
+#[test]
+fn jump_past_the_end_fails() {
+    // push u8 200, jmp
+    let mut vm = VM::new(vec![1, 0, 200, 17]);
+    let error = vm.execute().unwrap_err();
+    assert_eq!(error, VmError::InvalidJumpTarget { ip: 3, opcode: 17, target: 200 });
+    assert_eq!(error.to_string(), "jump target 200 is not the start of an instruction (opcode 17 at ip 3)");
+
+    // push u64 max, push u8 0, call u8 0
+    let mut bytecode = vec![1, 3];
+    bytecode.extend(u64::MAX.to_be_bytes());
+    bytecode.extend([27, 0, 0]);
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::InvalidJumpTarget { ip: 10, opcode: 27, target: u64::MAX as usize }));
+}
+
+#[test]
+fn jump_target_has_to_be_an_integer_that_is_not_negative() {
+    // push i8 -1, jmp
+    let mut vm = VM::new(vec![1, 4, 255, 17]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 3, opcode: 17 }));
+
+    // push f32 1e30, jmp
+    let mut bytecode = vec![1, 8];
+    bytecode.extend(1e30f32.to_be_bytes());
+    bytecode.push(17);
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 17 }));
+
+    // push f64 0, jmp
+    let mut bytecode = vec![1, 9];
+    bytecode.extend(0f64.to_be_bytes());
+    bytecode.push(17);
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 10, opcode: 17 }));
+
+    // push bool true, push bool true, jmp_if
+    let mut vm = VM::new(vec![1, 10, 1, 1, 10, 1, 25]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 25 }));
+
+    // save u8 1, get u8 0, jmp
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 17]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 17 }));
+
+    // push i16 5, jmp, nop
+    let vm = run(vec![1, 5, 0, 5, 17, 0]);
+    assert_eq!(vm.ip(), 6);
+}
+
+#[test]
+fn verify_reports_jumps_past_the_end() {
+    // 0: push u8 11, 3: jmp, 4: push u8 10, 7: call u8 0, calling the end of bytecode finishes
+    let problems = verify(&[1, 0, 11, 17, 1, 0, 10, 27, 0, 0]).unwrap_err().problems;
+    assert_eq!(problems, vec![VerifyProblem { offset: 3, reason: VerifyReason::JumpOutOfBounds(11) }]);
+    assert_eq!(problems[0].reason.to_string(), "jump target 11 is past the end of bytecode");
+}
+
+#[test]
+fn undecodable_bytecode_fails_when_executed() {
+    let mut vm = VM::new(vec![0, 1, 14, 0]);
+    assert_eq!(vm.execute(), Err(VmError::InvalidTypeTag { ip: 1, opcode: 1, tag: 14 }));
+    let mut vm = VM::new(vec![1, 1, 0]);
+    assert_eq!(vm.execute(), Err(VmError::TruncatedOperand { ip: 0, opcode: 1 }));
+    let mut vm = VM::new(vec![1, 0, 4, 17, 250]);
+    assert_eq!(vm.execute(), Err(VmError::UnknownOpcode { ip: 4, opcode: 250 }));
+}
+
+#[test]
+fn verify_accepts_valid_bytecode() {
+    // 0: push u8 5, 3: jmp, 4: nop, 5: gen u16 2, 15: load 0 as i32, 25: halt
+    let mut bytecode = vec![1, 0, 5, 17, 0, 9, 1];
+    bytecode.extend(2u64.to_be_bytes());
+    bytecode.push(12);
+    bytecode.extend(0u64.to_be_bytes());
+    bytecode.extend([6, 29]);
+
+    let program = verify(&bytecode).unwrap();
+    assert_eq!(program.offsets(), &[0, 3, 4, 5, 15, 25]);
+    assert_eq!(program.into_bytecode(), bytecode);
+}
+
+#[test]
+fn verify_reports_every_problem() {
+    let bytecode = vec![
+        1, 0, 5,    // 0: push u8 5, into the middle of push below
+        17,         // 3: jmp
+        1, 0, 1,    // 4: push u8 1
+        31, 11,     // 7: cast to unknown type
+        1, 14, 1,   // 9: push with unknown type tag, 11: push without operand after resync
+    ];
+    let problems = verify(&bytecode).unwrap_err().problems;
+    assert_eq!(problems, vec![
+        VerifyProblem { offset: 3, reason: VerifyReason::JumpIntoInstruction(5) },
+        VerifyProblem { offset: 7, reason: VerifyReason::InvalidValueType(11) },
+        VerifyProblem { offset: 9, reason: VerifyReason::InvalidTypeTag(14) },
+        VerifyProblem { offset: 11, reason: VerifyReason::TruncatedOperand },
+    ]);
+
+    let problems = verify(&[0, 1, 3, 0, 0, 40]).unwrap_err().problems;
+    assert_eq!(problems[0], VerifyProblem { offset: 1, reason: VerifyReason::TruncatedOperand });
+    let problems = verify(&[0, 200]).unwrap_err().problems;
+    assert_eq!(problems, vec![VerifyProblem { offset: 1, reason: VerifyReason::UnknownOpcode(200) }]);
+}
+
+#[test]
+fn verify_resyncs_after_undecodable_bytes() {
+    let bytecode = vec![
+        200,        // 0: unknown opcode
+        1, 14, 1,   // 1: push with unknown type tag, 2: less, 3: push without operand
+    ];
+    let problems = verify(&bytecode).unwrap_err().problems;
+    assert_eq!(problems, vec![
+        VerifyProblem { offset: 0, reason: VerifyReason::UnknownOpcode(200) },
+        VerifyProblem { offset: 1, reason: VerifyReason::InvalidTypeTag(14) },
+        VerifyProblem { offset: 3, reason: VerifyReason::TruncatedOperand },
+    ]);
+
+    // 0: 250, 1: push u8 9, 4: jmp, 5: 250, 6: nop
+    let problems = verify(&[250, 1, 0, 9, 17, 250, 0]).unwrap_err().problems;
+    assert_eq!(problems, vec![
+        VerifyProblem { offset: 0, reason: VerifyReason::UnknownOpcode(250) },
+        VerifyProblem { offset: 4, reason: VerifyReason::JumpOutOfBounds(9) },
+        VerifyProblem { offset: 5, reason: VerifyReason::UnknownOpcode(250) },
+    ]);
+}
+
+#[test]
+fn verify_reports_targets_it_can_not_compute() {
+    // 0: pop, 1: nop, 2: nop, 3: jmp, 4: push f32, 9: call u8 0
+    let problems = verify(&[2, 0, 0, 17, 1, 8, 0, 0, 128, 63, 27, 0, 0]).unwrap_err().problems;
+    assert_eq!(problems, vec![
+        VerifyProblem { offset: 3, reason: VerifyReason::UnverifiableTarget },
+        VerifyProblem { offset: 10, reason: VerifyReason::UnverifiableTarget },
+    ]);
+    assert_eq!(problems[0].reason.to_string(), "jump target is not known before the program runs");
+
+    // 0: push u8 7, 3: get u8 0, 6: jmp, get pushes to input in between
+    let problems = verify(&[1, 0, 7, 5, 0, 0, 17]).unwrap_err().problems;
+    assert_eq!(problems, vec![VerifyProblem { offset: 6, reason: VerifyReason::UnverifiableTarget }]);
+
+    // 0: push u8 4, 3: clear_o, 4: jmp, a jump lands between push and jump
+    let problems = verify(&[1, 0, 4, 8, 17]).unwrap_err().problems;
+    assert_eq!(problems, vec![VerifyProblem { offset: 4, reason: VerifyReason::UnverifiableTarget }]);
+}
+
+#[test]
+fn verify_follows_targets_through_instructions_that_leave_input_alone() {
+    // 0: push u8 0, 3: clear_o, 4: save u8 1, 7: nop, 8: jmp_if
+    let program = verify(&[1, 0, 0, 8, 10, 0, 1, 0, 25]).unwrap();
+    assert_eq!(program.offsets(), &[0, 3, 4, 7, 8]);
+
+    // 0: push #0, 4: jmp, target comes from constants of container
+    let mut container = Container::new(vec![1, 13, 0, 0, 17]);
+    container.constants.push(Constant::Value(Immediate::U16(5)));
+    assert!(verify_container(&container).is_ok());
+    assert_eq!(verify(&container.code).unwrap_err().problems[0].reason, VerifyReason::UnverifiableTarget);
+
+    container.constants[0] = Constant::Value(Immediate::U16(2));
+    let problems = verify_container(&container).unwrap_err().problems;
+    assert_eq!(problems, vec![VerifyProblem { offset: 4, reason: VerifyReason::JumpIntoInstruction(2) }]);
+}
+
+#[test]
+fn disassemble_renders_operands() {
+    let bytecode = vec![
+        1, 1, 0, 255, // push u16 255
+        4, 0, 3, 0, 2, // set u8 3 u8 2
+        12, 0, 0, 0, 0, 0, 0, 0, 4, 7, // load 4 i64
+        13, 0, 0, 0, 0, 0, 0, 0, 4, 12, 10, // loadi 4 output_max bool
+        18, // add
+    ];
+    let lines: Vec<String> = disassemble(&bytecode).iter().map(|instruction| instruction.to_string()).collect();
+    assert_eq!(lines, vec![
+        "    0: push U16(255)",
+        "    4: set U8(3) -> input[2]",
+        "    9: load addr=4 as i64",
+        "   19: loadi addr=4 -> output[output_max] as bool",
+        "   30: add",
+    ]);
+}
+
+#[test]
+fn unknown_type_tags_are_rejected() {
+    for tag in [14, 100, 255] {
+        // push with unknown type tag
+        let bytecode = [1, tag, 0, 0];
+        assert_eq!(decode_at(&bytecode, 0), Err(Fault::InvalidTypeTag(tag)));
+        let problems = verify(&bytecode).unwrap_err().problems;
+        assert_eq!(problems[0], VerifyProblem { offset: 0, reason: VerifyReason::InvalidTypeTag(tag) });
+        assert_eq!(disassemble(&bytecode)[0].problem, Some(VerifyReason::InvalidTypeTag(tag)));
+        let mut vm = VM::new(bytecode.to_vec());
+        assert_eq!(vm.execute(), Err(VmError::InvalidTypeTag { ip: 0, opcode: 1, tag }));
+
+        // cast to unknown type
+        let bytecode = [31, tag];
+        let problems = verify(&bytecode).unwrap_err().problems;
+        assert_eq!(problems, vec![VerifyProblem { offset: 0, reason: VerifyReason::InvalidValueType(tag) }]);
+        assert_eq!(disassemble(&bytecode)[0].problem, Some(VerifyReason::InvalidValueType(tag)));
+    }
+}
+
+#[test]
+fn disassemble_flags_undecodable_bytes() {
+    let instructions = disassemble(&[255, 31, 11, 1, 0, 0, 0]);
+    let problems: Vec<_> = instructions.iter().map(|instruction| (instruction.offset, instruction.problem)).collect();
+    assert_eq!(problems, vec![
+        (0, Some(VerifyReason::UnknownOpcode(255))),
+        (1, Some(VerifyReason::InvalidValueType(11))), // cast to input_max
+        (2, Some(VerifyReason::TruncatedOperand)), // savei with u16 value of one byte
+        (3, None), // push u8 0
+        (6, None), // nop
+    ]);
+    assert_eq!(instructions[0].to_string(), "    0: .byte 0xff  ; unknown opcode 255");
+}
+
+#[test]
+fn container_round_trips() {
+    let container = Container {
+        code: vec![1, 0, 7, 29],
+        constants: vec![Constant::Value(Immediate::F64(2.5)), Constant::Bytes(b"fluid".to_vec())],
+        debug: Some(DebugInfo {
+            source: "main.fasm".to_string(),
+            lines: vec![SourceLine { offset: 0, line: 1 }, SourceLine { offset: 3, line: 2 }],
+        }),
+    };
+
+    let bytes = container.write().unwrap();
+    assert_eq!(&bytes[..4], b"FLDC");
+    assert_eq!(Container::read(&bytes).unwrap(), container);
+
+    let plain = Container::new(vec![0]);
+    assert_eq!(Container::read(&plain.write().unwrap()).unwrap(), plain);
+}
+
+#[test]
+fn container_rejects_incompatible_bytes() {
+    let bytes = Container::new(vec![0]).write().unwrap();
+    let with = |index: usize, value: u8| {
+        let mut bytes = bytes.clone();
+        bytes[index] = value;
+        Container::read(&bytes).unwrap_err()
+    };
+
+    assert!(matches!(with(0, b'X'), ContainerError::BadMagic));
+    assert!(matches!(with(5, 2), ContainerError::UnsupportedFormat(2)));
+    assert!(matches!(with(7, 9), ContainerError::IncompatibleIsa(9)));
+    assert!(matches!(with(11, 2), ContainerError::UnknownFlags(2)));
+    assert!(matches!(Container::read(&bytes[..bytes.len() - 1]), Err(ContainerError::Truncated)));
+    assert!(matches!(Container::read(&[bytes.clone(), vec![0]].concat()), Err(ContainerError::TrailingBytes)));
+    assert!(matches!(Container::read(b"FL"), Err(ContainerError::BadMagic)));
+
+    let address = Container { constants: vec![Constant::Value(Immediate::ADDRESS(Address::new(1, 0)))], ..Default::default() };
+    assert!(matches!(address.write(), Err(ContainerError::InvalidConstant(0))));
+}
+
+#[test]
+fn vm_from_file_checks_version() {
+    let path = std::env::temp_dir().join(format!("fluid-vm-test-{}.fluidc", std::process::id()));
+    Container::new(vec![1, 0, 7, 29]).write_file(&path).unwrap();
+    let state = VM::from_file(&path).unwrap().execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(Some(Immediate::U8(7))));
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[7] = ISA_VERSION as u8 + 1;
+    std::fs::write(&path, bytes).unwrap();
+    let error = VM::from_file(&path).err().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        error.to_string(),
+        format!("bytecode was built for ISA version {}, this VM runs version {}", ISA_VERSION + 1, ISA_VERSION),
+    );
+}
+
+#[test]
+fn constants_replace_operands() {
+    let container = Container {
+        code: vec![
+            1, 13, 0, 0, // push #0
+            1, 13, 0, 1, // push #1
+            29, // halt
+        ],
+        constants: vec![Constant::Bytes(b"fluid".to_vec()), Constant::Value(Immediate::I64(-5))],
+        debug: None,
+    };
+    let mut vm = VM::from_container(container);
+    assert_eq!(vm.heap_entries().count(), 1);
+
+    let state = vm.execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(Some(Immediate::I64(-5))));
+    assert_eq!(vm.input(), &[Immediate::ADDRESS(Address::new(0, 0))]);
+
+    vm.clear();
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(disassemble(&[1, 13, 0, 1])[0].text, "push #1");
+}
+
+#[test]
+fn constant_blobs_can_not_be_freed() {
+    let container = Container {
+        code: vec![
+            1, 13, 0, 0, // push #0
+            34, // free
+        ],
+        constants: vec![Constant::Bytes(b"fluid".to_vec())],
+        debug: None,
+    };
+    let mut vm = VM::from_container(container);
+    let address = Address::new(0, 0);
+    for _ in 0..2 {
+        assert_eq!(vm.execute(), Err(VmError::InvalidHeapAddress { ip: 4, opcode: 34, address }));
+        assert_eq!(vm.heap_entries().collect::<Vec<_>>(), vec![address]);
+        vm.clear();
+    }
+}
+
+#[test]
+fn missing_constant_fails() {
+    let bytecode = vec![0, 1, 13, 0, 2];
+    let error = VM::new(bytecode.clone()).execute().unwrap_err();
+    assert_eq!(error, VmError::InvalidConstant { ip: 1, opcode: 1, index: 2 });
+
+    assert!(verify(&bytecode).is_ok());
+    let problems = verify_container(&Container::new(bytecode)).unwrap_err().problems;
+    assert_eq!(problems, vec![VerifyProblem { offset: 1, reason: VerifyReason::InvalidConstant(2) }]);
+}
+
+#[test]
+fn save_pushes_address_since_isa_5() {
+    // 0: save u8 7, 3: savei u8 8 u8 0, 8: get 0, 11: push u64 1, 21: add
+    let mut bytecode = vec![10, 0, 7, 11, 0, 8, 0, 0, 5, 0, 0, 1, 3];
+    bytecode.extend(1u64.to_be_bytes());
+    bytecode.push(18);
+    let mut vm = VM::new(bytecode.clone());
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 21, opcode: 18 }));
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(1, 0))]);
+
+    // bytecode of ISA 4 got a u64 from save and could do arithmetic on it
+    let mut bytes = Container::new(bytecode).write().unwrap();
+    bytes[7] = 4;
+    assert!(matches!(Container::read(&bytes), Err(ContainerError::IncompatibleIsa(4))));
+}
+
+#[test]
+fn store_writes_value_to_saved_address() {
+    // save u16 5, push u16 9, get 0, store, load 0 as u16
+    let mut bytecode = vec![10, 1, 0, 5, 1, 1, 0, 9, 5, 0, 0, 33, 12];
+    bytecode.extend(0u64.to_be_bytes());
+    bytecode.push(1);
+    let vm = run(bytecode);
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 0)), Immediate::U16(9)]);
+
+    // 0: save u8 1, 3: push u16 2, 7: get 0, 10: store
+    let mut vm = VM::new(vec![10, 0, 1, 1, 1, 0, 2, 5, 0, 0, 33]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 10, opcode: 33 }));
+
+    // 0: gen u8 len=2, 10: push u8 1, 13: get 0, 16: store
+    let mut bytecode = vec![9, 0];
+    bytecode.extend(2u64.to_be_bytes());
+    bytecode.extend([1, 0, 1, 5, 0, 0, 33]);
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 16, opcode: 33 }));
+}
+
+#[test]
+fn free_releases_values_and_arrays() {
+    // save u8 1, gen u32 len=4, get 0, free, get 1, free
+    let mut bytecode = vec![10, 0, 1, 9, 2];
+    bytecode.extend(4u64.to_be_bytes());
+    bytecode.extend([5, 0, 0, 34, 5, 0, 1, 34]);
+    let vm = run(bytecode);
+    assert_eq!(vm.heap_entries().count(), 0);
+
+    // slot of freed address is reused with the next generation: save u8 1, get 0, free, save u8 2
+    let vm = run(vec![10, 0, 1, 5, 0, 0, 34, 10, 0, 2]);
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 0)), Immediate::ADDRESS(Address::new(0, 1))]);
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0]);
+
+    // 0: push u64 7, 10: free
+    let mut bytecode = vec![1, 3];
+    bytecode.extend(7u64.to_be_bytes());
+    bytecode.push(34);
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::InvalidHeapAddress { ip: 10, opcode: 34, address: Address::new(7, 0) }));
+}
+
+#[test]
+fn double_free_and_use_after_free_are_errors() {
+    // 0: save u8 1, 3: get 0, 6: free, 7: get 0, 10: free
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 34, 5, 0, 0, 34]);
+    let error = vm.execute().unwrap_err();
+    assert_eq!(error, VmError::DoubleFree { ip: 10, opcode: 34, address: Address::new(0, 0) });
+    assert_eq!(error.to_string(), "heap address 0 was already freed (opcode 34 at ip 10)");
+
+    // 0: save u8 1, 3: get 0, 6: free, 7: load 0 as u8
+    let mut bytecode = vec![10, 0, 1, 5, 0, 0, 34, 12];
+    bytecode.extend(0u64.to_be_bytes());
+    bytecode.push(0);
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::UseAfterFree { ip: 7, opcode: 12, address: Address::new(0, 0) }));
+
+    // 0: save u8 1, 3: get 0, 6: free, 7: push u8 2, 10: get 0, 13: store
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 34, 1, 0, 2, 5, 0, 0, 33]);
+    assert_eq!(vm.execute(), Err(VmError::UseAfterFree { ip: 13, opcode: 33, address: Address::new(0, 0) }));
+}
+
+#[test]
+fn array_elements_are_loaded_and_stored() {
+    // 0: gen u16 len=3, 10: push u16 7, 14: push u8 2, 17: get 0, 20: astore,
+    // 21: push u8 2, 24: get 0, 27: aload, 28: get 0, 31: alen
+    let mut bytecode = vec![9, 1];
+    bytecode.extend(3u64.to_be_bytes());
+    bytecode.extend([1, 1, 0, 7, 1, 0, 2, 5, 0, 0, 36, 1, 0, 2, 5, 0, 0, 35, 5, 0, 0, 37]);
+    let vm = run(bytecode);
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 0)), Immediate::U16(7), Immediate::U64(3)]);
+
+    // blobs of constants are arrays of u8: push u8 1, push #0, aload, push #0, alen
+    let mut container = Container::new(vec![1, 0, 1, 1, 13, 0, 0, 35, 1, 13, 0, 0, 37]);
+    container.constants.push(Constant::Bytes(b"hi".to_vec()));
+    let mut vm = VM::from_container(container);
+    vm.execute().unwrap();
+    assert_eq!(vm.output(), &[Immediate::U8(b'i'), Immediate::U64(2)]);
+}
+
+#[test]
+fn array_access_checks_bounds_and_types() {
+    let gen = |element_type: u8, rest: &[u8]| {
+        let mut bytecode = vec![9, element_type];
+        bytecode.extend(2u64.to_be_bytes());
+        bytecode.extend(rest);
+        VM::new(bytecode)
+    };
+
+    // 10: push u8 2, 13: get 0, 16: aload
+    let error = gen(0, &[1, 0, 2, 5, 0, 0, 35]).execute().unwrap_err();
+    assert_eq!(error, VmError::IndexOutOfBounds { ip: 16, opcode: 35, index: 2, length: 2 });
+    assert_eq!(error.to_string(), "index 2 is out of bounds of array of length 2 (opcode 35 at ip 16)");
+
+    // 10: push i8 -1, 13: get 0, 16: aload
+    let error = gen(0, &[1, 4, 0xff, 5, 0, 0, 35]).execute().unwrap_err();
+    assert_eq!(error, VmError::IndexOutOfBounds { ip: 16, opcode: 35, index: -1, length: 2 });
+
+    // 10: push f32 1, 16: get 0, 19: aload
+    let error = gen(0, &[1, 8, 0x3f, 0x80, 0, 0, 5, 0, 0, 35]).execute().unwrap_err();
+    assert_eq!(error, VmError::TypeMismatch { ip: 19, opcode: 35 });
+
+    // 10: push u8 7, 13: push u8 0, 16: get 0, 19: astore into array of u16
+    let error = gen(1, &[1, 0, 7, 1, 0, 0, 5, 0, 0, 36]).execute().unwrap_err();
+    assert_eq!(error, VmError::TypeMismatch { ip: 19, opcode: 36 });
+
+    // 0: save u8 1, 3: get 0, 6: alen
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 37]);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 37 }));
+}
+
+#[test]
+fn load_returns_value_with_type_it_was_saved_with() {
+    // save i32 -70000, load 0 as i32
+    let mut bytecode = vec![10, 6];
+    bytecode.extend((-70000i32).to_be_bytes());
+    bytecode.push(12);
+    bytecode.extend(0u64.to_be_bytes());
+    bytecode.push(6);
+    let vm = run(bytecode.clone());
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 0)), Immediate::I32(-70000)]);
+
+    // 0: save i32 -70000, 6: load 0 as i16
+    *bytecode.last_mut().unwrap() = 5;
+    let mut vm = VM::new(bytecode);
+    assert_eq!(vm.execute(), Err(VmError::TypeMismatch { ip: 6, opcode: 12 }));
+}
+
+#[test]
+fn gen_of_zero_length() {
+    // gen f64 len=0, get 0, alen
+    let mut bytecode = vec![9, 9];
+    bytecode.extend(0u64.to_be_bytes());
+    bytecode.extend([5, 0, 0, 37]);
+    let vm = run(bytecode);
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 0)), Immediate::U64(0)]);
+}
+
+#[test]
+fn collect_frees_unreachable_objects() {
+    // 0: gen u64 len=1, 10: save u8 7, 13: pop, 14: push u8 0, 17: get 0, 20: astore,
+    // 21: save u8 9, 24: pop, 25: clear_i
+    let mut bytecode = vec![9, 3];
+    bytecode.extend(1u64.to_be_bytes());
+    bytecode.extend([10, 0, 7, 2, 1, 0, 0, 5, 0, 0, 36, 10, 0, 9, 2, 7]);
+    let mut vm = run(bytecode);
+    assert_eq!(vm.heap_entries().count(), 3);
+
+    // saved u8 7 is reachable through the array in output, saved u8 9 is not
+    assert_eq!(vm.collect(), 1);
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(vm.gc_stats(), GcStats { collections: 1, freed: 1, live: 2 });
+
+    vm.clear();
+    assert_eq!(vm.gc_stats(), GcStats::default());
+}
+
+#[test]
+fn collect_keeps_objects_of_callers_and_constants() {
+    let mut container = Container::new(vec![
+        10, 0, 1,   // 0: save u8 1
+        1, 0, 10,   // 3: push u8 10
+        27, 0, 0,   // 6: call u8 0
+        29,         // 9: halt
+        10, 0, 2,   // 10: save u8 2
+        8,          // 13: clear_o
+        10, 0, 3,   // 14: save u8 3
+        28, 0, 0,   // 17: ret u8 0
+    ]);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let mut vm = VM::from_container(container);
+    vm.set_gc_threshold(Some(1));
+    vm.execute().unwrap();
+
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(1, 0))]);
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert_eq!(vm.gc_stats(), GcStats { collections: 2, freed: 1, live: 2 });
+}
+
+#[test]
+fn gc_threshold_collects_while_running() {
+    // save u8 1, clear_o, ten times
+    let bytecode = [10, 0, 1, 8].repeat(10);
+    let mut vm = VM::new(bytecode.clone());
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().count(), 10);
+    assert_eq!(vm.gc_stats().collections, 0);
+
+    let mut vm = VM::new(bytecode);
+    vm.set_gc_threshold(Some(4));
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().count(), 2);
+    assert_eq!(vm.gc_stats(), GcStats { collections: 2, freed: 8, live: 0 });
+}
+
+#[test]
+fn stale_addresses_are_detected_after_reuse() {
+    // 0: save u8 1, 3: get 0, 6: free, 7: save u8 2, 10: push u8 5, 13: get 0, 16: store
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 34, 10, 0, 2, 1, 0, 5, 5, 0, 0, 33]);
+    let error = vm.execute().unwrap_err();
+    assert_eq!(error, VmError::UseAfterFree { ip: 16, opcode: 33, address: Address::new(0, 0) });
+
+    // 0: save u8 1, 3: get 0, 6: free, 7: save u8 2, 10: get 0, 13: free
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 34, 10, 0, 2, 5, 0, 0, 34]);
+    assert_eq!(vm.execute(), Err(VmError::DoubleFree { ip: 13, opcode: 34, address: Address::new(0, 0) }));
+
+    // 0: save u8 1, 3: get 0, 6: free, 7: save u8 2, 10: load 0 as u8, 20: load of generation 1 as u8
+    let mut bytecode = vec![10, 0, 1, 5, 0, 0, 34, 10, 0, 2, 12];
+    bytecode.extend(0u64.to_be_bytes());
+    bytecode.extend([0, 12]);
+    bytecode.extend(Address::new(0, 1).to_u64().to_be_bytes());
+    bytecode.push(0);
+    let mut vm = VM::new(bytecode.clone());
+    assert_eq!(vm.execute(), Err(VmError::UseAfterFree { ip: 10, opcode: 12, address: Address::new(0, 0) }));
+
+    bytecode.drain(10..20);
+    let vm = run(bytecode);
+    assert_eq!(vm.output()[1..], [Immediate::ADDRESS(Address::new(0, 1)), Immediate::U8(2)]);
+
+    let error = VmError::UseAfterFree { ip: 0, opcode: 12, address: Address::new(3, 2) };
+    assert_eq!(error.to_string(), "heap address 3 (generation 2) was used after it was freed (opcode 12 at ip 0)");
+}
+
+#[test]
+fn reference_counting_frees_objects_without_references() {
+    // save u8 1, get 0, clear_o, clear_i
+    let bytecode = vec![10, 0, 1, 5, 0, 0, 8, 7];
+    let mut vm = VM::new(bytecode.clone());
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().count(), 1);
+
+    let mut vm = VM::new(bytecode);
+    vm.set_reference_counting(true);
+    vm.run_until(7).unwrap();
+    assert_eq!(vm.heap_entries().count(), 1);
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().count(), 0);
+
+    // values left in buffers of a function are released when it returns
+    let mut vm = VM::new(vec![
+        1, 0, 7,    // 0: push u8 7
+        27, 0, 0,   // 3: call u8 0
+        29,         // 6: halt
+        10, 0, 1,   // 7: save u8 1
+        10, 0, 2,   // 10: save u8 2
+        28, 0, 1,   // 13: ret u8 1
+    ]);
+    vm.set_reference_counting(true);
+    vm.execute().unwrap();
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(1, 0))]);
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![1]);
+}
+
+#[test]
+fn reference_counting_frees_in_order_references_are_dropped() {
+    let mut vm = VM::new(vec![
+        10, 0, 1,                       // 0: save u8 1
+        10, 3, 0, 0, 0, 0, 0, 0, 0, 0,  // 3: save u64 0
+        5, 0, 0,                        // 13: get 0
+        5, 0, 1,                        // 16: get 1
+        33,                             // 19: store
+        8,                              // 20: clear_o
+        10, 0, 5,                       // 21: save u8 5
+        10, 0, 6,                       // 24: save u8 6
+    ]);
+    vm.set_reference_counting(true);
+    vm.run_until(20).unwrap();
+    assert_eq!(vm.heap_entries().count(), 2);
+
+    // saved u64 is freed first and releases address of u8 it holds, which is freed after it
+    vm.step().unwrap();
+    assert_eq!(vm.heap_entries().count(), 0);
+    vm.execute().unwrap();
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 1)), Immediate::ADDRESS(Address::new(1, 1))]);
+
+    // constants keep their blobs and references released after free are ignored
+    let mut container = Container::new(vec![1, 13, 0, 0, 7, 10, 0, 1, 5, 0, 0, 34, 8]);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let mut vm = VM::from_container(container);
+    vm.set_reference_counting(true);
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0]);
+}
+
+#[test]
+fn reference_counting_releases_exit_value() {
+    // save u8 1, get 0, clear_o, halt
+    let mut vm = VM::new(vec![10, 0, 1, 5, 0, 0, 8, 29]);
+    vm.set_reference_counting(true);
+    let state = vm.execute().unwrap();
+    assert_eq!(state.status, ExitStatus::Halted(Some(Immediate::ADDRESS(Address::new(0, 0)))));
+    assert_eq!(vm.heap_entries().count(), 0);
+}
+
+#[test]
+fn reference_counting_counts_addresses_of_saved_values() {
+    let mut container = Container::new(vec![
+        10, 13, 0, 0,       // 0: save #0
+        8,                  // 4: clear_o, freeing saved address releases it
+        1, 13, 0, 0,        // 5: push #0
+        37,                 // 9: alen
+        11, 13, 0, 0, 0, 0, // 10: savei #0 -> output[u8 0]
+        8,                  // 16: clear_o
+    ]);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let mut vm = VM::from_container(container);
+    vm.set_reference_counting(true);
+    vm.execute().unwrap();
+    assert_eq!(vm.output(), &[]);
+    assert_eq!(vm.heap_entries().collect::<Vec<_>>(), vec![Address::new(0, 0)]);
+}
+
+#[test]
+fn reference_counting_follows_addresses_stored_in_arrays() {
+    // 0: gen u64 len=1, 10: save u8 7, 13: pop, 14: push u8 0, 17: get 0, 20: astore,
+    // 21: save u8 9, 24: pop, 25: push u8 0, 28: get 0, 31: astore, 32: clear_o
+    let mut bytecode = vec![9, 3];
+    bytecode.extend(1u64.to_be_bytes());
+    bytecode.extend([10, 0, 7, 2, 1, 0, 0, 5, 0, 0, 36, 10, 0, 9, 2, 1, 0, 0, 5, 0, 0, 36, 8]);
+    let mut vm = VM::new(bytecode);
+    vm.set_reference_counting(true);
+
+    // saved u8 7 is kept alive by the array after its address was popped from input
+    vm.run_until(21).unwrap();
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0, 1]);
+
+    // overwriting the element releases u8 7
+    vm.run_until(32).unwrap();
+    assert_eq!(vm.heap_entries().map(|address| address.index).collect::<Vec<_>>(), vec![0, 2]);
+
+    // freeing the array releases u8 9, also after a snapshot was restored
+    let mut restored = VM::restore(&vm.snapshot().unwrap()).unwrap();
+    for vm in [&mut vm, &mut restored] {
+        vm.execute().unwrap();
+        assert_eq!(vm.heap_entries().count(), 0);
+    }
+}
+
+#[test]
+fn indexes_have_to_be_integers_that_are_not_negative() {
+    let fails = |bytecode: Vec<u8>| VM::new(bytecode).execute().unwrap_err();
+
+    // set u8 1 -> input[i8 -1], set u8 1 -> input[f32 1.0], geti output[bool true] input[u8 0]
+    assert_eq!(fails(vec![4, 0, 1, 4, 255]), VmError::TypeMismatch { ip: 0, opcode: 4 });
+    assert_eq!(fails(vec![4, 0, 1, 8, 0x3f, 0x80, 0, 0]), VmError::TypeMismatch { ip: 0, opcode: 4 });
+    assert_eq!(fails(vec![6, 10, 1, 0, 0]), VmError::TypeMismatch { ip: 0, opcode: 6 });
+}
+
+#[test]
+fn buffers_can_not_grow_beyond_limit() {
+    let fails = |bytecode: Vec<u8>| VM::new(bytecode).execute().unwrap_err();
+
+    // set u8 1 -> input[u32 0x7fffffff]
+    let error = fails(vec![4, 0, 1, 2, 0x7f, 0xff, 0xff, 0xff]);
+    assert_eq!(error, VmError::BufferLimitExceeded { ip: 0, opcode: 4, index: 0x7fffffff, limit: MAX_BUFFER_LEN });
+    assert_eq!(error.to_string(), format!("index 2147483647 exceeds buffer limit of {} values (opcode 4 at ip 0)", MAX_BUFFER_LEN));
+
+    // set u8 1 -> input[u64 max], savei u8 1 output[u64 max]
+    let mut bytecode = vec![4, 0, 1, 3];
+    bytecode.extend(u64::MAX.to_be_bytes());
+    assert_eq!(fails(bytecode), VmError::BufferLimitExceeded { ip: 0, opcode: 4, index: usize::MAX, limit: MAX_BUFFER_LEN });
+    let mut bytecode = vec![11, 0, 1, 3];
+    bytecode.extend(u64::MAX.to_be_bytes());
+    assert_eq!(fails(bytecode), VmError::BufferLimitExceeded { ip: 0, opcode: 11, index: usize::MAX, limit: MAX_BUFFER_LEN });
+
+    // set u8 1 -> input[u8 1], set u8 1 -> input[u8 2]
+    let mut vm = VM::new(vec![4, 0, 1, 0, 1, 4, 0, 1, 0, 2]);
+    vm.set_max_buffer_len(2);
+    assert_eq!(vm.execute(), Err(VmError::BufferLimitExceeded { ip: 5, opcode: 4, index: 2, limit: 2 }));
+    assert_eq!(vm.input(), &[Immediate::NONE(), Immediate::U8(1)]);
+}
+
+#[test]
+fn huge_allocations_fail_instead_of_aborting() {
+    let gen = |element_type: u8, length: u64| {
+        let mut bytecode = vec![9, element_type];
+        bytecode.extend(length.to_be_bytes());
+        VM::new(bytecode).execute()
+    };
+
+    let error = gen(0, u64::MAX).unwrap_err();
+    assert_eq!(error, VmError::HeapLimitExceeded { ip: 0, opcode: 9, limit: HeapLimit::Allocation, size: usize::MAX });
+    assert_eq!(
+        error.to_string(),
+        format!("allocation of {} bytes exceeds limit of size of a single allocation (opcode 9 at ip 0)", usize::MAX),
+    );
+
+    // size of elements overflows
+    let error = gen(3, 1 << 61).unwrap_err();
+    assert_eq!(error, VmError::HeapLimitExceeded { ip: 0, opcode: 9, limit: HeapLimit::Allocation, size: usize::MAX });
+
+    let error = gen(3, (DEFAULT_MAX_ALLOCATION / 8 + 1) as u64).unwrap_err();
+    assert_eq!(error, VmError::HeapLimitExceeded { ip: 0, opcode: 9, limit: HeapLimit::Allocation, size: DEFAULT_MAX_ALLOCATION + 8 });
+
+    // tag is checked before size
+    assert_eq!(gen(11, u64::MAX), Err(VmError::InvalidTypeTag { ip: 0, opcode: 9, tag: 11 }));
+}
+
+#[test]
+fn heap_limits_bytes_and_objects() {
+    // 0: gen u64 len=2, 10: save u8 1
+    let mut bytecode = vec![9, 3];
+    bytecode.extend(2u64.to_be_bytes());
+    bytecode.extend([10, 0, 1]);
+
+    let limits = HeapLimits { max_bytes: 16, ..HeapLimits::default() };
+    let mut vm = VM::with_limits(Container::new(bytecode.clone()), limits);
+    assert_eq!(vm.execute(), Err(VmError::HeapLimitExceeded { ip: 10, opcode: 10, limit: HeapLimit::Bytes, size: 1 }));
+    assert_eq!(vm.heap_entries().count(), 1);
+
+    let limits = HeapLimits { max_objects: 1, ..HeapLimits::default() };
+    let mut vm = VM::with_limits(Container::new(bytecode.clone()), limits);
+    assert_eq!(vm.execute(), Err(VmError::HeapLimitExceeded { ip: 10, opcode: 10, limit: HeapLimit::Objects, size: 1 }));
+
+    let limits = HeapLimits { max_bytes: 17, max_objects: 2, ..HeapLimits::default() };
+    let mut vm = VM::with_limits(Container::new(bytecode), limits);
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().count(), 2);
+
+    // blobs of constants count towards limits
+    let mut container = Container::new(vec![10, 0, 1]);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let mut vm = VM::with_limits(container, HeapLimits { max_bytes: 4, ..HeapLimits::default() });
+    assert_eq!(vm.execute(), Err(VmError::HeapLimitExceeded { ip: 0, opcode: 10, limit: HeapLimit::Bytes, size: 1 }));
+}
+
+#[test]
+fn execution_resumes_after_heap_limit_is_freed() {
+    // 0: save u8 1, 3: clear_o, 4: save u8 2
+    let limits = HeapLimits { max_objects: 1, ..HeapLimits::default() };
+    let mut vm = VM::with_limits(Container::new(vec![10, 0, 1, 8, 10, 0, 2]), limits);
+    assert_eq!(vm.execute(), Err(VmError::HeapLimitExceeded { ip: 4, opcode: 10, limit: HeapLimit::Objects, size: 1 }));
+    assert_eq!(vm.ip(), 4);
+
+    assert_eq!(vm.collect(), 1);
+    vm.execute().unwrap();
+    assert_eq!(vm.output(), &[Immediate::ADDRESS(Address::new(0, 1))]);
+
+    // collection that runs before an allocation makes room for it
+    let limits = HeapLimits { max_objects: 1, ..HeapLimits::default() };
+    let mut vm = VM::with_limits(Container::new(vec![10, 0, 1, 8, 10, 0, 2]), limits);
+    vm.set_gc_threshold(Some(1));
+    vm.execute().unwrap();
+    assert_eq!(vm.heap_entries().count(), 1);
+}
+
+#[test]
+fn heap_objects_have_type_size_and_ip() {
+    // 0: save u8 1, 3: gen f64 len=2, 13: clear_o, 14: save i16 -2
+    let mut bytecode = vec![10, 0, 1, 9, 9];
+    bytecode.extend(2u64.to_be_bytes());
+    bytecode.extend([8, 10, 5, 0xff, 0xfe]);
+    let mut container = Container::new(bytecode);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let mut vm = VM::from_container(container);
+    vm.set_gc_threshold(Some(2));
+    vm.execute().unwrap();
+
+    let info = |index, generation, tag, length, size, ip| HeapObjectInfo { address: Address::new(index, generation), tag, length, size, ip };
+    assert_eq!(vm.heap_objects().collect::<Vec<_>>(), vec![
+        info(0, 0, 0, Some(4), 4, None),
+        info(2, 1, 5, None, 2, Some(14)),
+    ]);
+    assert_eq!(vm.heap_stats(), HeapStats {
+        objects: 2,
+        bytes: 6,
+        peak_bytes: 21,
+        slots: 3,
+        free_slots: 1,
+        allocated: 4,
+        freed: 2,
+    });
+
+    vm.clear();
+    assert_eq!(vm.heap_stats(), HeapStats { objects: 1, bytes: 4, peak_bytes: 4, slots: 1, allocated: 1, ..HeapStats::default() });
+}
+
+#[test]
+fn leaks_are_reported_on_clear_and_drop() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // 0: save u8 1, 3: save i16 -2, 7: get 0, 10: free
+    let mut container = Container::new(vec![10, 0, 1, 10, 5, 0xff, 0xfe, 5, 0, 0, 34]);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::from_container(container);
+    let sink = Rc::clone(&reports);
+    vm.set_leak_report(move |objects| sink.borrow_mut().push(objects.to_vec()));
+    vm.execute().unwrap();
+
+    let leak = HeapObjectInfo { address: Address::new(2, 0), tag: 5, length: None, size: 2, ip: Some(3) };
+    assert_eq!(vm.leaks().collect::<Vec<_>>(), vec![leak]);
+
+    vm.clear();
+    assert_eq!(vm.heap_entries().count(), 1);
+    assert_eq!(vm.leaks().count(), 0);
+
+    // nothing is reported if every object was freed
+    vm.clear();
+    vm.run_until(3).unwrap();
+    drop(vm);
+    let leak_u8 = HeapObjectInfo { address: Address::new(1, 0), tag: 0, length: None, size: 1, ip: Some(0) };
+    assert_eq!(*reports.borrow(), vec![vec![leak], vec![leak_u8]]);
+}
+
+#[test]
+fn snapshot_restores_running_vm() {
+    let mut bytecode = vec![9, 0];
+    bytecode.extend(3u64.to_be_bytes());
+    bytecode.extend([
+        10, 8, 0x3f, 0xc0, 0, 0,    // 10: save f32 1.5
+        5, 0, 1,                    // 16: get u8 1
+        34,                         // 19: free
+        1, 0, 27,                   // 20: push u8 27
+        27, 0, 0,                   // 23: call u8 0
+        29,                         // 26: halt
+        10, 5, 0xff, 0xfe,          // 27: save i16 -2
+        28, 0, 1,                   // 31: ret u8 1
+    ]);
+    let mut container = Container::new(bytecode);
+    container.constants.push(Constant::Bytes(b"blob".to_vec()));
+    let mut vm = VM::from_container(container);
+    vm.set_gc_threshold(Some(8));
+    vm.run_until(31).unwrap();
+
+    let snapshot = vm.snapshot().unwrap();
+    let mut restored = VM::restore(&snapshot).unwrap();
+    assert_eq!(restored.snapshot().unwrap(), snapshot);
+    assert_eq!((restored.ip(), restored.call_depth()), (31, 1));
+    assert_eq!(restored.output(), vm.output());
+    assert_eq!(restored.heap_objects().collect::<Vec<_>>(), vm.heap_objects().collect::<Vec<_>>());
+    assert_eq!(restored.heap_stats(), vm.heap_stats());
+
+    let state = vm.execute().unwrap();
+    assert_eq!(restored.execute().unwrap(), state);
+    assert_eq!(restored.output(), vm.output());
+    assert_eq!(restored.input(), vm.input());
+    assert_eq!(restored.heap_objects().collect::<Vec<_>>(), vm.heap_objects().collect::<Vec<_>>());
+    assert_eq!(restored.gc_stats(), vm.gc_stats());
+}
+
+#[test]
+fn snapshot_rejects_invalid_bytes() {
+    let snapshot = VM::new(vec![1, 0, 7, 1, 0, 8]).snapshot().unwrap();
+    assert!(VM::restore(&snapshot).is_ok());
+    assert!(matches!(VM::restore(b"FLDC"), Err(SnapshotError::BadMagic)));
+
+    let mut bytes = snapshot.clone();
+    bytes[5] = 2;
+    let error = VM::restore(&bytes).err().unwrap();
+    assert!(matches!(error, SnapshotError::UnsupportedVersion(2)));
+    assert_eq!(error.to_string(), "unsupported snapshot version 2, this VM reads version 1");
+
+    // ISA version of program
+    let mut bytes = snapshot.clone();
+    bytes[17] = 3;
+    assert!(matches!(VM::restore(&bytes), Err(SnapshotError::Program(ContainerError::IncompatibleIsa(3)))));
+
+    assert!(matches!(VM::restore(&snapshot[..snapshot.len() - 1]), Err(SnapshotError::Truncated)));
+    let mut bytes = snapshot.clone();
+    bytes.push(0);
+    assert!(matches!(VM::restore(&bytes), Err(SnapshotError::TrailingBytes)));
+
+    // ip in the middle of push u8 8
+    let mut vm = VM::new(vec![1, 0, 7, 1, 0, 8]);
+    vm.step().unwrap();
+    let mut bytes = vm.snapshot().unwrap();
+    let ip = 14 + u32::from_be_bytes(bytes[6..10].try_into().unwrap()) as usize;
+    assert_eq!(bytes[ip..ip + 8], 3u64.to_be_bytes());
+    bytes[ip + 7] = 4;
+    assert!(matches!(VM::restore(&bytes), Err(SnapshotError::InvalidState)));
+}
@@ -1,8 +1,44 @@
-use std::fmt::Debug;
-use std::ops::{Add, Sub, Mul, Div};
+use std::fmt;
+use std::ops::Neg;
 
-pub type Address = usize;
+/// handle of an object on heap
+///
+/// generation of a slot of heap changes whenever its object is freed, so handle of a freed object
+/// is detected instead of referring to the object allocated later at the same index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Address {
+    pub index: u32,
+    pub generation: u32,
+}
+
+impl Address {
+    pub const fn new(index: u32, generation: u32) -> Self {
+        Address { index, generation }
+    }
+
+    /// returns handle as u64 with generation in the upper 32 bits, addresses are stored in arrays of u64
+    /// and written in operands like this
+    pub const fn to_u64(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    pub const fn from_u64(v: u64) -> Self {
+        Address { index: v as u32, generation: (v >> 32) as u32 }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.index)?;
+        if self.generation != 0 {
+            write!(f, " (generation {})", self.generation)?;
+        }
+
+        Ok(())
+    }
+}
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum Immediate {
     NONE(),
@@ -23,114 +59,269 @@ pub enum Immediate {
     ADDRESS(Address),
 }
 
-impl Add for Immediate {
-    type Output = Self;
-
-    fn add(self, other: Self) -> Self {
-        let val1 = self;
-        let val2 = other;
-        let mut v = Immediate::NONE();
-        match (val1, val2) {
-            (Immediate::U8(v1), Immediate::U8(v2)) => { v = Immediate::U8(v1 + v2); },
-            (Immediate::U16(v1), Immediate::U16(v2)) => { v = Immediate::U16(v1 + v2); },
-            (Immediate::U32(v1), Immediate::U32(v2)) => { v = Immediate::U32(v1 + v2); },
-            (Immediate::U64(v1), Immediate::U64(v2)) => { v = Immediate::U64(v1 + v2); },
-            (Immediate::I8(v1), Immediate::I8(v2)) => { v = Immediate::I8(v1 + v2); },
-            (Immediate::I16(v1), Immediate::I16(v2)) => { v = Immediate::I16(v1 + v2); },
-            (Immediate::I32(v1), Immediate::I32(v2)) => { v = Immediate::I32(v1 + v2); },
-            (Immediate::I64(v1), Immediate::I64(v2)) => { v = Immediate::I64(v1 + v2); },
-            (Immediate::F32(v1), Immediate::F32(v2)) => { v = Immediate::F32(v1 + v2); },
-            (Immediate::F64(v1), Immediate::F64(v2)) => { v = Immediate::F64(v1 + v2); },
-            (Immediate::BOOL(v1), Immediate::BOOL(v2)) => { v = Immediate::BOOL((v1 as u8 + v2 as u8) == 1); },
-            (Immediate::ADDRESS(v1), Immediate::ADDRESS(v2)) => { v = Immediate::ADDRESS(v1 + v2); },
-
-            _ => { v = Immediate::NONE(); }
+/// what integer arithmetic does when result does not fit into its type
+///
+/// floats follow IEEE 754 and never overflow, BOOL is computed as u8 0 or 1
+/// and is true if result is 1, ADDRESS is a handle and has no arithmetic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// overflow is an error
+    #[default]
+    Trap,
+    /// result wraps around at the boundary of the type
+    Wrap,
+    /// result is clamped to the minimum or maximum of the type
+    Saturate,
+}
+
+/// reason why arithmetic on immediates failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticError {
+    /// operands have different types or the operation is not defined for their type
+    TypeMismatch,
+    /// integer result does not fit into its type and overflow is Trap
+    Overflow,
+    /// integer was divided by zero, this is an error under every overflow
+    DivisionByZero,
+}
+
+/// number an immediate converts through when it is cast
+enum Number {
+    Integer(i128),
+    Float(f64),
+}
+
+/// applies integer operation with overflow
+macro_rules! integer {
+    ($overflow:expr, $checked:ident, $wrapping:ident, $saturating:ident; $v1:expr $(, $v2:expr)?) => {
+        match $overflow {
+            Overflow::Trap => $v1.$checked($($v2)?).ok_or(ArithmeticError::Overflow)?,
+            Overflow::Wrap => $v1.$wrapping($($v2)?),
+            Overflow::Saturate => $v1.$saturating($($v2)?),
         }
+    };
+}
 
-        return v;
-    }
+/// generates binary arithmetic method of Immediate
+macro_rules! binary {
+    ($(#[$doc:meta])* $name:ident, $checked:ident, $wrapping:ident, $saturating:ident, $op:tt, $zero:expr) => {
+        $(#[$doc])*
+        pub fn $name(self, other: Self, overflow: Overflow) -> Result<Self, ArithmeticError> {
+            if $zero && other.is_integer_zero() {
+                return Err(ArithmeticError::DivisionByZero);
+            }
+
+            let value = match (self, other) {
+                (Immediate::U8(v1), Immediate::U8(v2)) => Immediate::U8(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::U16(v1), Immediate::U16(v2)) => Immediate::U16(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::U32(v1), Immediate::U32(v2)) => Immediate::U32(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::U64(v1), Immediate::U64(v2)) => Immediate::U64(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::I8(v1), Immediate::I8(v2)) => Immediate::I8(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::I16(v1), Immediate::I16(v2)) => Immediate::I16(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::I32(v1), Immediate::I32(v2)) => Immediate::I32(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::I64(v1), Immediate::I64(v2)) => Immediate::I64(integer!(overflow, $checked, $wrapping, $saturating; v1, v2)),
+                (Immediate::F32(v1), Immediate::F32(v2)) => Immediate::F32(v1 $op v2),
+                (Immediate::F64(v1), Immediate::F64(v2)) => Immediate::F64(v1 $op v2),
+                (Immediate::BOOL(v1), Immediate::BOOL(v2)) => Immediate::BOOL(integer!(overflow, $checked, $wrapping, $saturating; v1 as u8, v2 as u8) == 1),
+
+                _ => return Err(ArithmeticError::TypeMismatch),
+            };
+
+            Ok(value)
+        }
+    };
 }
 
-impl Sub for Immediate {
-    type Output = Self;
-
-    fn sub(self, other: Self) -> Self {
-        let val1 = self;
-        let val2 = other;
-        let mut v = Immediate::NONE();
-        match (val1, val2) {
-            (Immediate::U8(v1), Immediate::U8(v2)) => { v = Immediate::U8(v1 - v2); },
-            (Immediate::U16(v1), Immediate::U16(v2)) => { v = Immediate::U16(v1 - v2); },
-            (Immediate::U32(v1), Immediate::U32(v2)) => { v = Immediate::U32(v1 - v2); },
-            (Immediate::U64(v1), Immediate::U64(v2)) => { v = Immediate::U64(v1 - v2); },
-            (Immediate::I8(v1), Immediate::I8(v2)) => { v = Immediate::I8(v1 - v2); },
-            (Immediate::I16(v1), Immediate::I16(v2)) => { v = Immediate::I16(v1 - v2); },
-            (Immediate::I32(v1), Immediate::I32(v2)) => { v = Immediate::I32(v1 - v2); },
-            (Immediate::I64(v1), Immediate::I64(v2)) => { v = Immediate::I64(v1 - v2); },
-            (Immediate::F32(v1), Immediate::F32(v2)) => { v = Immediate::F32(v1 - v2); },
-            (Immediate::F64(v1), Immediate::F64(v2)) => { v = Immediate::F64(v1 - v2); },
-            (Immediate::BOOL(v1), Immediate::BOOL(v2)) => { v = Immediate::BOOL((v1 as u8 - v2 as u8) == 1); },
-            (Immediate::ADDRESS(v1), Immediate::ADDRESS(v2)) => { v = Immediate::ADDRESS(v1 - v2); },
-
-            _ => { v = Immediate::NONE(); }
+/// generates unary arithmetic method of Immediate for signed numbers and floats
+macro_rules! unary {
+    ($(#[$doc:meta])* $name:ident, $checked:ident, $wrapping:ident, $saturating:ident, $float:ident, $unsigned:expr) => {
+        $(#[$doc])*
+        pub fn $name(self, overflow: Overflow) -> Result<Self, ArithmeticError> {
+            let value = match self {
+                Immediate::U8(_) | Immediate::U16(_) | Immediate::U32(_) | Immediate::U64(_) => {
+                    if !$unsigned {
+                        return Err(ArithmeticError::TypeMismatch);
+                    }
+                    self
+                }
+                Immediate::I8(v) => Immediate::I8(integer!(overflow, $checked, $wrapping, $saturating; v)),
+                Immediate::I16(v) => Immediate::I16(integer!(overflow, $checked, $wrapping, $saturating; v)),
+                Immediate::I32(v) => Immediate::I32(integer!(overflow, $checked, $wrapping, $saturating; v)),
+                Immediate::I64(v) => Immediate::I64(integer!(overflow, $checked, $wrapping, $saturating; v)),
+                Immediate::F32(v) => Immediate::F32(v.$float()),
+                Immediate::F64(v) => Immediate::F64(v.$float()),
+
+                _ => return Err(ArithmeticError::TypeMismatch),
+            };
+
+            Ok(value)
         }
+    };
+}
+
+impl Immediate {
+    binary!(
+        /// adds two numbers
+        add, checked_add, wrapping_add, saturating_add, +, false
+    );
+
+    binary!(
+        /// substracts other from self
+        sub, checked_sub, wrapping_sub, saturating_sub, -, false
+    );
+
+    binary!(
+        /// multiplies two numbers
+        mul, checked_mul, wrapping_mul, saturating_mul, *, false
+    );
 
-        return v;
+    binary!(
+        /// divides self by other, integer division by zero fails
+        div, checked_div, wrapping_div, saturating_div, /, true
+    );
+
+    binary!(
+        /// returns remainder of self divided by other, integer division by zero fails,
+        /// remainder of MIN and -1 is 0 when overflow is Saturate
+        rem, checked_rem, wrapping_rem, wrapping_rem, %, true
+    );
+
+    unary!(
+        /// negates signed number or float, fails for other types
+        neg, checked_neg, wrapping_neg, saturating_neg, neg, false
+    );
+
+    unary!(
+        /// returns absolute value, unsigned numbers are returned as they are
+        abs, checked_abs, wrapping_abs, saturating_abs, abs, true
+    );
+
+    /// converts value to type of tag, tags are numbered like operands of push
+    ///
+    /// integers are truncated or sign extended, floats are truncated towards zero and
+    /// saturated to the range of the integer with NaN becoming 0, BOOL is 0 or 1 and any
+    /// nonzero value becomes true, ADDRESS converts as u64 of its handle
+    ///
+    /// when checked, integers that do not fit, floats that are not finite or do not fit after
+    /// truncation and finite f64 that is out of range of f32 fail with Overflow
+    pub fn cast(self, tag: u8, checked: bool) -> Result<Self, ArithmeticError> {
+        let number = match self {
+            Immediate::U8(v) => Number::Integer(v as i128),
+            Immediate::U16(v) => Number::Integer(v as i128),
+            Immediate::U32(v) => Number::Integer(v as i128),
+            Immediate::U64(v) => Number::Integer(v as i128),
+            Immediate::I8(v) => Number::Integer(v as i128),
+            Immediate::I16(v) => Number::Integer(v as i128),
+            Immediate::I32(v) => Number::Integer(v as i128),
+            Immediate::I64(v) => Number::Integer(v as i128),
+            Immediate::F32(v) => Number::Float(v as f64),
+            Immediate::F64(v) => Number::Float(v),
+            Immediate::BOOL(v) => Number::Integer(v as i128),
+            Immediate::ADDRESS(v) => Number::Integer(v.to_u64() as i128),
+            Immediate::NONE() => return Err(ArithmeticError::TypeMismatch),
+        };
+
+        match number {
+            Number::Integer(v) => Immediate::from_integer(tag, v, checked),
+            Number::Float(v) => Immediate::from_float(tag, v, checked),
+        }
     }
-}
 
-impl Mul for Immediate {
-    type Output = Self;
-
-    fn mul(self, other: Self) -> Self {
-        let val1 = self;
-        let val2 = other;
-        let mut v = Immediate::NONE();
-        match (val1, val2) {
-            (Immediate::U8(v1), Immediate::U8(v2)) => { v = Immediate::U8(v1 * v2); },
-            (Immediate::U16(v1), Immediate::U16(v2)) => { v = Immediate::U16(v1 * v2); },
-            (Immediate::U32(v1), Immediate::U32(v2)) => { v = Immediate::U32(v1 * v2); },
-            (Immediate::U64(v1), Immediate::U64(v2)) => { v = Immediate::U64(v1 * v2); },
-            (Immediate::I8(v1), Immediate::I8(v2)) => { v = Immediate::I8(v1 * v2); },
-            (Immediate::I16(v1), Immediate::I16(v2)) => { v = Immediate::I16(v1 * v2); },
-            (Immediate::I32(v1), Immediate::I32(v2)) => { v = Immediate::I32(v1 * v2); },
-            (Immediate::I64(v1), Immediate::I64(v2)) => { v = Immediate::I64(v1 * v2); },
-            (Immediate::F32(v1), Immediate::F32(v2)) => { v = Immediate::F32(v1 * v2); },
-            (Immediate::F64(v1), Immediate::F64(v2)) => { v = Immediate::F64(v1 * v2); },
-            (Immediate::BOOL(v1), Immediate::BOOL(v2)) => { v = Immediate::BOOL((v1 as u8 * v2 as u8) == 1); },
-            (Immediate::ADDRESS(v1), Immediate::ADDRESS(v2)) => { v = Immediate::ADDRESS(v1 * v2); },
-
-            _ => { v = Immediate::NONE(); }
+    /// converts integer to type of tag
+    fn from_integer(tag: u8, v: i128, checked: bool) -> Result<Self, ArithmeticError> {
+        macro_rules! convert {
+            ($variant:ident, $t:ty) => {
+                if checked {
+                    Immediate::$variant(<$t>::try_from(v).map_err(|_| ArithmeticError::Overflow)?)
+                } else {
+                    Immediate::$variant(v as $t)
+                }
+            };
         }
 
-        return v;
+        let value = match tag {
+            0 => convert!(U8, u8), // u8
+            1 => convert!(U16, u16), // u16
+            2 => convert!(U32, u32), // u32
+            3 => convert!(U64, u64), // u64
+            4 => convert!(I8, i8), // i8
+            5 => convert!(I16, i16), // i16
+            6 => convert!(I32, i32), // i32
+            7 => convert!(I64, i64), // i64
+            8 => Immediate::F32(v as f32), // f32
+            9 => Immediate::F64(v as f64), // f64
+            10 => Immediate::BOOL(v != 0), // bool
+            _ => return Err(ArithmeticError::TypeMismatch),
+        };
+
+        Ok(value)
     }
-}
 
-impl Div for Immediate {
-    type Output = Self;
-
-    fn div(self, other: Self) -> Self {
-        let val1 = self;
-        let val2 = other;
-        let mut v = Immediate::NONE();
-        match (val1, val2) {
-            (Immediate::U8(v1), Immediate::U8(v2)) => { v = Immediate::U8(v1 / v2); },
-            (Immediate::U16(v1), Immediate::U16(v2)) => { v = Immediate::U16(v1 / v2); },
-            (Immediate::U32(v1), Immediate::U32(v2)) => { v = Immediate::U32(v1 / v2); },
-            (Immediate::U64(v1), Immediate::U64(v2)) => { v = Immediate::U64(v1 / v2); },
-            (Immediate::I8(v1), Immediate::I8(v2)) => { v = Immediate::I8(v1 / v2); },
-            (Immediate::I16(v1), Immediate::I16(v2)) => { v = Immediate::I16(v1 / v2); },
-            (Immediate::I32(v1), Immediate::I32(v2)) => { v = Immediate::I32(v1 / v2); },
-            (Immediate::I64(v1), Immediate::I64(v2)) => { v = Immediate::I64(v1 / v2); },
-            (Immediate::F32(v1), Immediate::F32(v2)) => { v = Immediate::F32(v1 / v2); },
-            (Immediate::F64(v1), Immediate::F64(v2)) => { v = Immediate::F64(v1 / v2); },
-            (Immediate::BOOL(v1), Immediate::BOOL(v2)) => { v = Immediate::BOOL((v1 as u8 / v2 as u8) == 1); },
-            (Immediate::ADDRESS(v1), Immediate::ADDRESS(v2)) => { v = Immediate::ADDRESS(v1 / v2); },
-
-            _ => { v = Immediate::NONE(); }
+    /// converts float to type of tag
+    fn from_float(tag: u8, v: f64, checked: bool) -> Result<Self, ArithmeticError> {
+        if checked {
+            match tag {
+                0..=7 if !v.is_finite() => return Err(ArithmeticError::Overflow),
+                0..=7 => return Immediate::from_integer(tag, v as i128, true),
+                8 if v.is_finite() && (v as f32).is_infinite() => return Err(ArithmeticError::Overflow),
+                _ => {}
+            }
         }
 
-        return v;
+        let value = match tag {
+            0 => Immediate::U8(v as u8), // u8
+            1 => Immediate::U16(v as u16), // u16
+            2 => Immediate::U32(v as u32), // u32
+            3 => Immediate::U64(v as u64), // u64
+            4 => Immediate::I8(v as i8), // i8
+            5 => Immediate::I16(v as i16), // i16
+            6 => Immediate::I32(v as i32), // i32
+            7 => Immediate::I64(v as i64), // i64
+            8 => Immediate::F32(v as f32), // f32
+            9 => Immediate::F64(v), // f64
+            10 => Immediate::BOOL(v != 0.0), // bool
+            _ => return Err(ArithmeticError::TypeMismatch),
+        };
+
+        Ok(value)
+    }
+
+    /// returns type tag followed by big endian value as it is encoded in an immediate operand,
+    /// None for NONE and ADDRESS because they have no type tag
+    pub fn encode(self) -> Option<Vec<u8>> {
+        let (tag, mut bytes) = match self {
+            Immediate::U8(v) => (0, v.to_be_bytes().to_vec()),
+            Immediate::U16(v) => (1, v.to_be_bytes().to_vec()),
+            Immediate::U32(v) => (2, v.to_be_bytes().to_vec()),
+            Immediate::U64(v) => (3, v.to_be_bytes().to_vec()),
+            Immediate::I8(v) => (4, v.to_be_bytes().to_vec()),
+            Immediate::I16(v) => (5, v.to_be_bytes().to_vec()),
+            Immediate::I32(v) => (6, v.to_be_bytes().to_vec()),
+            Immediate::I64(v) => (7, v.to_be_bytes().to_vec()),
+            Immediate::F32(v) => (8, v.to_be_bytes().to_vec()),
+            Immediate::F64(v) => (9, v.to_be_bytes().to_vec()),
+            Immediate::BOOL(v) => (10, vec![v as u8]),
+            Immediate::NONE() | Immediate::ADDRESS(_) => return None,
+        };
+
+        bytes.insert(0, tag);
+        Some(bytes)
+    }
+
+    /// returns true if value is integer zero, floats are never integer zero
+    fn is_integer_zero(&self) -> bool {
+        match *self {
+            Immediate::U8(v) => v == 0,
+            Immediate::U16(v) => v == 0,
+            Immediate::U32(v) => v == 0,
+            Immediate::U64(v) => v == 0,
+            Immediate::I8(v) => v == 0,
+            Immediate::I16(v) => v == 0,
+            Immediate::I32(v) => v == 0,
+            Immediate::I64(v) => v == 0,
+            Immediate::BOOL(v) => !v,
+
+            _ => false,
+        }
     }
-}
\ No newline at end of file
+}
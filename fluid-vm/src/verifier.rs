@@ -0,0 +1,220 @@
+use std::fmt;
+
+use crate::container::*;
+use crate::decoder::*;
+use crate::tools::*;
+
+/// reason why bytecode failed verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyReason {
+    /// opcode has no instruction assigned
+    UnknownOpcode(u8),
+    /// operand runs past the end of bytecode
+    TruncatedOperand,
+    /// type tag of immediate operand is not 0..=13
+    InvalidTypeTag(u8),
+    /// type tag of gen, load or cast is not 0..=10
+    InvalidValueType(u8),
+    /// target of jump or call lands inside of an instruction
+    JumpIntoInstruction(usize),
+    /// target of jump or call is past the end of bytecode
+    JumpOutOfBounds(usize),
+    /// target of jump or call is not known before the program runs
+    UnverifiableTarget,
+    /// operand refers to a constant the program does not have
+    InvalidConstant(u16),
+}
+
+impl VerifyReason {
+    /// returns reason of bytecode that could not be decoded at opcode
+    pub(crate) fn from_fault(fault: Fault, opcode: u8) -> Self {
+        match fault {
+            Fault::UnknownOpcode => VerifyReason::UnknownOpcode(opcode),
+            Fault::TruncatedOperand => VerifyReason::TruncatedOperand,
+            Fault::InvalidTypeTag(tag) => VerifyReason::InvalidTypeTag(tag),
+            Fault::InvalidConstant(index) => VerifyReason::InvalidConstant(index),
+        }
+    }
+}
+
+/// problem found by verify at offset of an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyProblem {
+    pub offset: usize,
+    pub reason: VerifyReason,
+}
+
+/// every problem found by verify, ordered by offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub problems: Vec<VerifyProblem>,
+}
+
+/// bytecode that passed verify
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedProgram {
+    bytecode: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl VerifiedProgram {
+    pub fn bytecode(&self) -> &[u8] {
+        &self.bytecode
+    }
+
+    /// returns offset of every instruction
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    pub fn into_bytecode(self) -> Vec<u8> {
+        self.bytecode
+    }
+}
+
+/// checks every instruction of bytecode before it is executed
+///
+/// every problem is collected, after an unknown opcode, an unknown type tag of immediate operand
+/// or a truncated operand walking resyncs at the next byte
+///
+/// the target of a jump or call has to be an integer pushed before it with only instructions in
+/// between that leave input alone and are no jump targets, other targets are reported as
+/// unverifiable, constants are not checked because bytecode does not have them
+pub fn verify(bytecode: &[u8]) -> Result<VerifiedProgram, VerifyError> {
+    verify_code(bytecode, None)
+}
+
+/// checks code of container like verify, checks that every operand refers to one of its constants
+/// and follows targets pushed from constants
+pub fn verify_container(container: &Container) -> Result<VerifiedProgram, VerifyError> {
+    verify_code(&container.code, Some(&container.constants))
+}
+
+fn verify_code(bytecode: &[u8], constants: Option<&[Constant]>) -> Result<VerifiedProgram, VerifyError> {
+    let mut problems = Vec::new();
+    let mut offsets = Vec::new();
+    let mut targets = Vec::new();
+    let mut pushed = None;
+    let mut offset = 0;
+
+    while offset < bytecode.len() {
+        let (instruction, next) = match decode_at(bytecode, offset) {
+            Ok(decoded) => decoded,
+            Err(fault) => {
+                let reason = VerifyReason::from_fault(fault, bytecode[offset]);
+                problems.push(VerifyProblem { offset, reason });
+                pushed = None;
+                offset += 1;
+                continue;
+            }
+        };
+
+        let exists = |value| match value {
+            Value::Constant(index) if constants.is_some_and(|constants| index as usize >= constants.len()) => {
+                Err(Fault::InvalidConstant(index))
+            }
+            value => Ok(value),
+        };
+        if let Err(fault) = instruction.map_values(exists) {
+            problems.push(VerifyProblem { offset, reason: VerifyReason::from_fault(fault, bytecode[offset]) });
+        }
+
+        match instruction {
+            Instruction::Gen(tag, _) | Instruction::Load(_, tag) | Instruction::Loadi(_, _, tag)
+            | Instruction::Cast(tag) | Instruction::Castc(tag) if tag > 10 => {
+                problems.push(VerifyProblem { offset, reason: VerifyReason::InvalidValueType(tag) });
+            }
+
+            Instruction::Jmp | Instruction::JmpIf | Instruction::JmpUnless | Instruction::Call(_) => {
+                match pushed {
+                    Some((push, target)) => targets.push((offset, push, target)),
+                    None => problems.push(VerifyProblem { offset, reason: VerifyReason::UnverifiableTarget }),
+                }
+            }
+
+            _ => {}
+        }
+
+        pushed = match instruction {
+            Instruction::Push(Value::Immediate(value)) => integer(value).map(|target| (offset, target)),
+            Instruction::Push(Value::Constant(index)) => match constants.and_then(|constants| constants.get(index as usize)) {
+                Some(&Constant::Value(value)) => integer(value).map(|target| (offset, target)),
+                _ => None,
+            },
+            Instruction::Nop | Instruction::ClearO | Instruction::Gen(..) | Instruction::Save(_)
+            | Instruction::Savei(..) | Instruction::Load(..) | Instruction::Loadi(..) | Instruction::Ovf(_) => pushed,
+            _ => None,
+        };
+        offsets.push(offset);
+        offset = next;
+    }
+
+    // a jump into the instructions between push and jump can arrive with another target on input
+    let known: Vec<usize> = targets.iter().map(|&(_, _, target)| target)
+        .filter(|target| offsets.binary_search(target).is_ok())
+        .collect();
+    for (jump, push, target) in targets {
+        if target > bytecode.len() {
+            problems.push(VerifyProblem { offset: jump, reason: VerifyReason::JumpOutOfBounds(target) });
+        } else if target < offset && offsets.binary_search(&target).is_err() {
+            problems.push(VerifyProblem { offset: jump, reason: VerifyReason::JumpIntoInstruction(target) });
+        } else if known.iter().any(|&known| known > push && known <= jump) {
+            problems.push(VerifyProblem { offset: jump, reason: VerifyReason::UnverifiableTarget });
+        }
+    }
+
+    if !problems.is_empty() {
+        problems.sort_by_key(|problem| problem.offset);
+        return Err(VerifyError { problems });
+    }
+
+    Ok(VerifiedProgram {
+        bytecode: bytecode.to_vec(),
+        offsets,
+    })
+}
+
+/// returns value of integer immediate as it is used for jump target, None if it is negative
+fn integer(value: Immediate) -> Option<usize> {
+    let value = match value {
+        Immediate::U8(v) => v as usize,
+        Immediate::U16(v) => v as usize,
+        Immediate::U32(v) => v as usize,
+        Immediate::U64(v) => usize::try_from(v).unwrap_or(usize::MAX),
+        Immediate::I8(v) => usize::try_from(v).ok()?,
+        Immediate::I16(v) => usize::try_from(v).ok()?,
+        Immediate::I32(v) => usize::try_from(v).ok()?,
+        Immediate::I64(v) => usize::try_from(v).ok()?,
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+impl fmt::Display for VerifyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            VerifyReason::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            VerifyReason::TruncatedOperand => write!(f, "operand runs past the end of bytecode"),
+            VerifyReason::InvalidTypeTag(tag) => write!(f, "invalid type tag {}", tag),
+            VerifyReason::InvalidValueType(tag) => write!(f, "invalid value type {}", tag),
+            VerifyReason::JumpIntoInstruction(target) => write!(f, "jump target {} is inside of an instruction", target),
+            VerifyReason::JumpOutOfBounds(target) => write!(f, "jump target {} is past the end of bytecode", target),
+            VerifyReason::UnverifiableTarget => write!(f, "jump target is not known before the program runs"),
+            VerifyReason::InvalidConstant(index) => write!(f, "constant #{} does not exist", index),
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bytecode failed verification with {} problem(s)", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  offset {}: {}", problem.offset, problem.reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerifyError {}